@@ -0,0 +1,79 @@
+use message;
+use decoder::{Decoder, DecoderError, SampleFormat};
+use samples::{SampleBuffer, SampleType};
+use claxon;
+use claxon::frame::FrameReader;
+use claxon::metadata::StreamInfo;
+use std::io::Cursor;
+
+/// Decoder for the "flac" codec.
+///
+/// The codec header holds the FLAC stream header (`fLaC` marker and metadata
+/// blocks including STREAMINFO), each wire chunk one or more complete FLAC
+/// frames.
+pub struct FLACDecoder {
+    streaminfo: Option<StreamInfo>,
+}
+
+impl Decoder for FLACDecoder {
+    fn decode(&mut self, chunk: &[u8]) -> Result<SampleBuffer, DecoderError> {
+        let streaminfo = match self.streaminfo {
+            Some(s) => s,
+            None => return Err(DecoderError::NoHeader),
+        };
+        // Samples are aligned to the msb of the smallest sample type that
+        // holds them, e.g. 20 bit samples are emitted as S24.
+        let sample_type = SampleType::for_bits(streaminfo.bits_per_sample as u16);
+        let width = match sample_type {
+            SampleType::S16 => 16,
+            SampleType::S24 => 24,
+            _ => 32,
+        };
+        let shift = width - streaminfo.bits_per_sample;
+        let mut samples = Vec::new();
+        let mut reader = FrameReader::new(Cursor::new(chunk));
+        let mut buffer = Vec::new();
+        loop {
+            match reader.read_next_or_eof(buffer) {
+                Ok(Some(block)) => {
+                    for i in 0..block.duration() {
+                        for ch in 0..block.channels() {
+                            samples.push(block.sample(ch, i) << shift);
+                        }
+                    }
+                    buffer = block.into_buffer();
+                },
+                Ok(None) => break,
+                Err(e) => {
+                    if samples.is_empty() {
+                        return Err(DecoderError::InvalidData(e.to_string()));
+                    }
+                    warn!("Error decoding FLAC frame: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok(match sample_type {
+            SampleType::S16 => SampleBuffer::S16(samples.into_iter().map(|s| s as i16).collect()),
+            SampleType::S24 => SampleBuffer::S24(samples),
+            _ => SampleBuffer::S32(samples),
+        })
+    }
+    fn set_header(&mut self, header: message::CodecHeaderData) -> Result<SampleFormat, DecoderError> {
+        self.streaminfo = None;
+        let reader = claxon::FlacReader::new(Cursor::new(header.payload))
+            .map_err(|e| DecoderError::InvalidHeader(e.to_string()))?;
+        let streaminfo = reader.streaminfo();
+        self.streaminfo = Some(streaminfo);
+        Ok(SampleFormat {
+            rate: streaminfo.sample_rate,
+            channels: streaminfo.channels as u16,
+            bits: streaminfo.bits_per_sample as u16,
+        })
+    }
+    fn new() -> Self {
+        FLACDecoder {
+            streaminfo: None,
+        }
+    }
+}
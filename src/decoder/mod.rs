@@ -1,29 +1,140 @@
 use message;
+use samples::SampleBuffer;
+use std::error;
+use std::fmt;
+
+use std::collections::HashMap;
 
 mod pcm_decoder;
 pub use self::pcm_decoder::PCMDecoder;
+#[cfg(feature = "flac")]
+mod flac_decoder;
+#[cfg(feature = "flac")]
+pub use self::flac_decoder::FLACDecoder;
+#[cfg(feature = "ogg")]
+mod ogg_decoder;
+#[cfg(feature = "ogg")]
+pub use self::ogg_decoder::OggDecoder;
+#[cfg(feature = "opus")]
+mod opus_decoder;
+#[cfg(feature = "opus")]
+pub use self::opus_decoder::OpusDecoder;
+
+/// Backend-neutral description of a decoded stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampleFormat {
+    pub rate: u32,
+    pub channels: u16,
+    /// Bit depth of the source stream.
+    pub bits: u16,
+}
+
+/// Errors reported by decoders.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DecoderError {
+    /// The codec header could not be parsed.
+    InvalidHeader(String),
+    /// A chunk could not be decoded.
+    InvalidData(String),
+    /// Audio was passed before a valid codec header.
+    NoHeader,
+    /// No decoder is registered for the codec.
+    UnknownCodec(String),
+}
 
-use alsa::pcm::{PCM, HwParams, Format, Access, State};
+impl fmt::Display for DecoderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &DecoderError::InvalidHeader(ref e) => write!(f, "invalid codec header: {}", e),
+            &DecoderError::InvalidData(ref e) => write!(f, "invalid audio data: {}", e),
+            &DecoderError::NoHeader => write!(f, "no codec header received"),
+            &DecoderError::UnknownCodec(ref c) => write!(f, "unsupported codec \"{}\"", c),
+        }
+    }
+}
+
+impl error::Error for DecoderError {
+    fn description(&self) -> &str {
+        match self {
+            &DecoderError::InvalidHeader(_) => "invalid codec header",
+            &DecoderError::InvalidData(_) => "invalid audio data",
+            &DecoderError::NoHeader => "no codec header received",
+            &DecoderError::UnknownCodec(_) => "unsupported codec",
+        }
+    }
+}
 
+/// Turns the codec header and the wire chunks of one codec into interleaved
+/// samples, in the sample type closest to the bit depth of the stream.
+///
+/// A decoder lives for one stream and may keep state across chunks. How the
+/// audio is output is up to the caller, based on the `SampleFormat` returned
+/// by `set_header`.
 pub trait Decoder {
-    fn decode(&self, chunk: Vec<u8>) -> Vec<i16>;
-    fn setHeader(&self, header: message::CodecHeaderData);
+    /// Configures the decoder from the codec header and returns the format
+    /// of the audio it will produce.
+    fn set_header(&mut self, header: message::CodecHeaderData) -> Result<SampleFormat, DecoderError>;
+    /// Decodes one wire chunk.
+    fn decode(&mut self, chunk: &[u8]) -> Result<SampleBuffer, DecoderError>;
+    /// Produces `frames` frames of audio to fill in for a lost chunk.
+    /// Decoders that cannot conceal losses return nothing, which is played
+    /// as silence.
+    fn conceal(&mut self, _frames: usize) -> SampleBuffer {
+        SampleBuffer::S16(Vec::new())
+    }
     fn new() -> Self where Self: Sized;
-    fn get_hwparams(&self, pcm: &HwParams);
 }
 
-pub struct DummyDecoder;
+/// Creates a fresh decoder for one stream.
+pub type DecoderFactory = Box<Fn() -> Box<Decoder>>;
+
+/// Maps the codec names sent in codec headers to decoders.
+///
+/// `DecoderRegistry::new()` contains the codecs compiled into this build,
+/// selected with the `flac`, `ogg` and `opus` cargo features. Further codecs
+/// can be added with `register`, which replaces an existing codec of the same
+/// name.
+pub struct DecoderRegistry {
+    factories: HashMap<String, DecoderFactory>,
+}
 
-impl Decoder for DummyDecoder {
-    fn decode(&self, chunk: Vec<u8>) -> Vec<i16> {
-        Vec::new()
+impl DecoderRegistry {
+    /// Creates a registry with the built-in codecs.
+    pub fn new() -> DecoderRegistry {
+        let mut registry = DecoderRegistry::empty();
+        registry.register("pcm", Box::new(|| Box::new(PCMDecoder::new())));
+        #[cfg(feature = "flac")]
+        registry.register("flac", Box::new(|| Box::new(FLACDecoder::new())));
+        #[cfg(feature = "ogg")]
+        registry.register("ogg", Box::new(|| Box::new(OggDecoder::new())));
+        #[cfg(feature = "opus")]
+        registry.register("opus", Box::new(|| Box::new(OpusDecoder::new())));
+        registry
     }
-    fn setHeader(&self, header: message::CodecHeaderData) {
 
+    /// Creates a registry without any codecs.
+    pub fn empty() -> DecoderRegistry {
+        DecoderRegistry {
+            factories: HashMap::new(),
+        }
     }
-    fn new() -> Self {
-        Self {}
+
+    pub fn register(&mut self, codec: &str, factory: DecoderFactory) {
+        self.factories.insert(codec.to_string(), factory);
     }
-    fn get_hwparams(&self, pcm: &HwParams) { }
-}
 
+    /// Names of the registered codecs, sorted.
+    pub fn codecs(&self) -> Vec<&str> {
+        let mut codecs: Vec<&str> = self.factories.keys().map(|c| c.as_str()).collect();
+        codecs.sort();
+        codecs
+    }
+
+    /// Creates a decoder for `codec`.
+    pub fn create(&self, codec: &str) -> Result<Box<Decoder>, DecoderError> {
+        match self.factories.get(codec) {
+            Some(factory) => Ok(factory()),
+            None => Err(DecoderError::UnknownCodec(codec.to_string())),
+        }
+    }
+}
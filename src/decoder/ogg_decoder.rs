@@ -0,0 +1,231 @@
+use message;
+use decoder::{Decoder, DecoderError, SampleFormat};
+use samples::SampleBuffer;
+use lewton::audio::{read_audio_packet, PreviousWindowRight};
+use lewton::header::{read_header_ident, read_header_comment, read_header_setup, IdentHeader, SetupHeader};
+
+const PAGE_HEADER_SIZE: usize = 27;
+/// Header type flag marking a page that continues a packet of the previous
+/// page.
+const CONTINUED_PACKET: u8 = 0x01;
+
+/// Reassembles Ogg packets from a byte stream that may be split at arbitrary
+/// positions, e.g. Ogg pages spanning several wire chunks.
+#[derive(Debug, Default)]
+struct OggReader {
+    buf: Vec<u8>,
+    /// Packet continued on the next page.
+    packet: Vec<u8>,
+}
+
+impl OggReader {
+    fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Returns all packets completed by the buffered pages.
+    fn packets(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        loop {
+            // Resynchronize on the capture pattern.
+            match self.buf.windows(4).position(|w| w == b"OggS") {
+                Some(0) => {},
+                Some(i) => {
+                    warn!("Skipping {} bytes of garbage in Ogg stream", i);
+                    self.buf.drain(..i);
+                },
+                None => {
+                    let keep = self.buf.len().min(3);
+                    let skip = self.buf.len() - keep;
+                    self.buf.drain(..skip);
+                    return packets;
+                }
+            }
+            if self.buf.len() < PAGE_HEADER_SIZE {
+                return packets;
+            }
+            let segments = self.buf[26] as usize;
+            let header_len = PAGE_HEADER_SIZE + segments;
+            if self.buf.len() < header_len {
+                return packets;
+            }
+            let body_len: usize = self.buf[PAGE_HEADER_SIZE..header_len].iter().map(|&l| l as usize).sum();
+            if self.buf.len() < header_len + body_len {
+                return packets;
+            }
+            let continued = self.buf[5] & CONTINUED_PACKET != 0;
+            if !continued && !self.packet.is_empty() {
+                warn!("Dropping incomplete Ogg packet");
+                self.packet.clear();
+            }
+            // The start of a continued packet was dropped or skipped while
+            // resynchronizing, skip the rest of it too.
+            let mut skip = continued && self.packet.is_empty();
+            let mut pos = header_len;
+            for i in PAGE_HEADER_SIZE..header_len {
+                let len = self.buf[i] as usize;
+                if !skip {
+                    self.packet.extend_from_slice(&self.buf[pos..pos + len]);
+                }
+                pos += len;
+                // A lacing value below 255 terminates the packet.
+                if len < 255 {
+                    if skip {
+                        warn!("Dropping Ogg packet without its start");
+                        skip = false;
+                    } else {
+                        packets.push(self.packet.split_off(0));
+                    }
+                }
+            }
+            self.buf.drain(..pos);
+        }
+    }
+}
+
+struct VorbisState {
+    ident: IdentHeader,
+    setup: SetupHeader,
+    pwr: PreviousWindowRight,
+}
+
+/// Decoder for the "ogg" codec, carrying Vorbis.
+///
+/// The codec header holds the Ogg pages with the identification, comment and
+/// setup headers, the wire chunks the subsequent pages. Pages may be split
+/// across chunks, so incomplete data is kept until the next chunk arrives.
+pub struct OggDecoder {
+    reader: OggReader,
+    vorbis: Option<VorbisState>,
+}
+
+impl OggDecoder {
+    fn read_headers(packets: &[Vec<u8>]) -> Result<VorbisState, DecoderError> {
+        if packets.len() < 3 {
+            return Err(DecoderError::InvalidHeader(
+                format!("expected 3 header packets, got {}", packets.len())));
+        }
+        let invalid = |e| DecoderError::InvalidHeader(format!("{:?}", e));
+        let ident = read_header_ident(&packets[0]).map_err(&invalid)?;
+        let comment = read_header_comment(&packets[1]).map_err(&invalid)?;
+        debug!("Vorbis vendor: {}", comment.vendor);
+        let setup = read_header_setup(&packets[2], ident.audio_channels,
+            (ident.blocksize_0, ident.blocksize_1)).map_err(&invalid)?;
+        Ok(VorbisState {
+            ident: ident,
+            setup: setup,
+            pwr: PreviousWindowRight::new(),
+        })
+    }
+}
+
+impl Decoder for OggDecoder {
+    fn decode(&mut self, chunk: &[u8]) -> Result<SampleBuffer, DecoderError> {
+        let state = match self.vorbis {
+            Some(ref mut s) => s,
+            None => return Err(DecoderError::NoHeader),
+        };
+        self.reader.feed(chunk);
+        let mut samples = Vec::new();
+        for packet in self.reader.packets() {
+            match read_audio_packet(&state.ident, &state.setup, &packet, &mut state.pwr) {
+                Ok(channels) => {
+                    let frames = channels.get(0).map(|c| c.len()).unwrap_or(0);
+                    for i in 0..frames {
+                        for c in channels.iter() {
+                            samples.push(c[i]);
+                        }
+                    }
+                },
+                Err(e) => warn!("Error decoding Vorbis packet: {:?}", e),
+            }
+        }
+        Ok(SampleBuffer::S16(samples))
+    }
+    fn set_header(&mut self, header: message::CodecHeaderData) -> Result<SampleFormat, DecoderError> {
+        self.vorbis = None;
+        self.reader = OggReader::default();
+        self.reader.feed(&header.payload);
+        let packets = self.reader.packets();
+        let state = OggDecoder::read_headers(&packets)?;
+        let format = SampleFormat {
+            rate: state.ident.audio_sample_rate,
+            channels: state.ident.audio_channels as u16,
+            bits: 16,
+        };
+        self.vorbis = Some(state);
+        Ok(format)
+    }
+    fn new() -> Self {
+        OggDecoder {
+            reader: OggReader::default(),
+            vorbis: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an Ogg page holding `body`, split into segments by `lacing`.
+    fn page(flags: u8, lacing: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut v = b"OggS".to_vec();
+        v.push(0);
+        v.push(flags);
+        // Granule position, serial number, sequence number and CRC.
+        v.extend_from_slice(&[0; 20]);
+        v.push(lacing.len() as u8);
+        v.extend_from_slice(lacing);
+        v.extend_from_slice(body);
+        v
+    }
+
+    fn read(data: &[u8]) -> Vec<Vec<u8>> {
+        let mut reader = OggReader::default();
+        reader.feed(data);
+        reader.packets()
+    }
+
+    #[test]
+    fn reassembles_pages_split_at_any_offset() {
+        let mut stream = page(0, &[3, 2], &[1, 2, 3, 4, 5]);
+        stream.extend(page(0, &[4], &[6, 7, 8, 9]));
+        let expected = vec![vec![1, 2, 3], vec![4, 5], vec![6, 7, 8, 9]];
+        for split in 0..stream.len() + 1 {
+            let mut reader = OggReader::default();
+            reader.feed(&stream[..split]);
+            let mut packets = reader.packets();
+            reader.feed(&stream[split..]);
+            packets.extend(reader.packets());
+            assert_eq!(packets, expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn joins_packet_continued_on_next_page() {
+        let first: Vec<u8> = (0..255).map(|i| i as u8).collect();
+        let mut stream = page(0, &[255], &first);
+        stream.extend(page(CONTINUED_PACKET, &[10, 1], &[7; 11]));
+        let mut expected = first.clone();
+        expected.extend_from_slice(&[7; 10]);
+        assert_eq!(read(&stream), vec![expected, vec![7]]);
+    }
+
+    #[test]
+    fn skips_garbage() {
+        let mut stream = b"garbage Ogg".to_vec();
+        stream.extend(page(0, &[2], &[1, 2]));
+        stream.extend_from_slice(b"more garbage");
+        stream.extend(page(0, &[1], &[3]));
+        assert_eq!(read(&stream), vec![vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn drops_continuation_without_start() {
+        // The page starting the packet was lost.
+        let mut stream = page(CONTINUED_PACKET, &[255, 5, 3], &[9; 263]);
+        stream.extend(page(0, &[2], &[1, 2]));
+        assert_eq!(read(&stream), vec![vec![9; 3], vec![1, 2]]);
+    }
+}
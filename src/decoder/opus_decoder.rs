@@ -0,0 +1,127 @@
+use message;
+use decoder::{Decoder, DecoderError, SampleFormat};
+use samples::SampleBuffer;
+use audiopus;
+use audiopus::{Channels, SampleRate};
+use audiopus::packet::Packet;
+use audiopus::MutSignals;
+use byteorder::{ByteOrder, LittleEndian};
+use std::convert::TryFrom;
+
+/// Marker at the start of the snapcast Opus codec header ("OPUS").
+const ID_OPUS: u32 = 0x4F50_5553;
+/// Longest Opus packet is 120ms, i.e. 5760 frames at 48kHz.
+const MAX_FRAMES: usize = 5760;
+
+/// Stream parameters from the snapcast Opus codec header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OpusHeader {
+    sample_rate: u32,
+    bits: u16,
+    channels: u16,
+}
+
+impl OpusHeader {
+    /// Parses the 12 byte header: marker, sample rate, bits and channels.
+    fn parse(data: &[u8]) -> Result<OpusHeader, DecoderError> {
+        if data.len() < 12 {
+            return Err(DecoderError::InvalidHeader(format!("header too short ({} bytes)", data.len())));
+        }
+        let id = LittleEndian::read_u32(&data[0..4]);
+        if id != ID_OPUS {
+            return Err(DecoderError::InvalidHeader(format!("invalid marker {:#010x}", id)));
+        }
+        Ok(OpusHeader {
+            sample_rate: LittleEndian::read_u32(&data[4..8]),
+            bits: LittleEndian::read_u16(&data[8..10]),
+            channels: LittleEndian::read_u16(&data[10..12]),
+        })
+    }
+}
+
+struct OpusState {
+    header: OpusHeader,
+    decoder: audiopus::coder::Decoder,
+}
+
+/// Decoder for the "opus" codec. Each wire chunk carries one Opus packet.
+///
+/// Missing audio can be replaced by Opus packet loss concealment through
+/// `conceal`.
+pub struct OpusDecoder {
+    state: Option<OpusState>,
+}
+
+impl OpusDecoder {
+    fn create(header: OpusHeader) -> Result<OpusState, DecoderError> {
+        let invalid = |e: audiopus::Error| DecoderError::InvalidHeader(e.to_string());
+        let rate = SampleRate::try_from(header.sample_rate as i32).map_err(&invalid)?;
+        let channels = Channels::try_from(header.channels as i32).map_err(&invalid)?;
+        let decoder = audiopus::coder::Decoder::new(rate, channels).map_err(&invalid)?;
+        Ok(OpusState {
+            header: header,
+            decoder: decoder,
+        })
+    }
+
+    /// Decodes `packet`, or conceals `frames` frames if it is `None`.
+    fn decode_packet(state: &mut OpusState, packet: Option<&[u8]>, frames: usize) -> Result<Vec<i16>, DecoderError> {
+        let channels = state.header.channels as usize;
+        let mut samples = vec![0i16; frames * channels];
+        let packet = match packet {
+            Some(p) => Some(Packet::try_from(p).map_err(|e| DecoderError::InvalidData(e.to_string()))?),
+            None => None,
+        };
+        let n = MutSignals::try_from(&mut samples)
+            .and_then(|out| state.decoder.decode(packet, out, false))
+            .map_err(|e| DecoderError::InvalidData(e.to_string()))?;
+        samples.truncate(n * channels);
+        Ok(samples)
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn decode(&mut self, chunk: &[u8]) -> Result<SampleBuffer, DecoderError> {
+        match self.state {
+            Some(ref mut state) => OpusDecoder::decode_packet(state, Some(chunk), MAX_FRAMES).map(SampleBuffer::S16),
+            None => Err(DecoderError::NoHeader),
+        }
+    }
+    fn conceal(&mut self, frames: usize) -> SampleBuffer {
+        let state = match self.state {
+            Some(ref mut s) => s,
+            None => return SampleBuffer::S16(Vec::new()),
+        };
+        // Concealment works in multiples of 2.5ms up to the packet maximum.
+        let step = state.header.sample_rate as usize / 400;
+        let mut samples = Vec::new();
+        let mut remaining = frames - frames % step;
+        while remaining > 0 {
+            let n = remaining.min(MAX_FRAMES);
+            match OpusDecoder::decode_packet(state, None, n) {
+                Ok(concealed) => samples.extend(concealed),
+                Err(e) => {
+                    warn!("Error concealing lost Opus packet: {}", e);
+                    break;
+                }
+            }
+            remaining -= n;
+        }
+        SampleBuffer::S16(samples)
+    }
+    fn set_header(&mut self, header: message::CodecHeaderData) -> Result<SampleFormat, DecoderError> {
+        self.state = None;
+        let header = OpusHeader::parse(&header.payload)?;
+        self.state = Some(OpusDecoder::create(header)?);
+        Ok(SampleFormat {
+            rate: header.sample_rate,
+            channels: header.channels,
+            bits: header.bits,
+        })
+    }
+    fn new() -> Self {
+        OpusDecoder {
+            state: None,
+        }
+    }
+}
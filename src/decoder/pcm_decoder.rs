@@ -1,62 +1,274 @@
 use message;
-use decoder::Decoder;
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use std::ops::Range;
-use hound;
-use std::io::Cursor;
-use std::mem;
-
-use alsa::pcm::{PCM, HwParams, Format, Access, State};
-use alsa::{Direction, ValueOr};
-
-#[repr(C, packed)]
-#[derive(Debug, Clone)]
-struct RiffHeader {
-    riff_id: u32,
-    riff_size: u32,
-    wave_id: u32,
-    id: u32,
-    size: u32,
-    audio_format: i16,
-    num_channels: i16,
-    sample_rate: u32,
-    byte_rate: u32,
-    block_align: i16,
-    bits_per_sample: i16,
-}
-
-pub struct PCMDecoder;
+use decoder::{Decoder, DecoderError, SampleFormat};
+use samples::SampleBuffer;
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
 
-impl Decoder for PCMDecoder {
-    fn decode(&self, chunk: Vec<u8>) -> Vec<i16> {
+const WAVE_FORMAT_PCM: u16 = 0x0001;
+const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Errors found while parsing the RIFF/WAVE codec header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RiffError {
+    /// The header ended in the middle of a chunk.
+    Truncated,
+    /// The data does not start with a RIFF/WAVE header.
+    NotWave,
+    /// No `fmt ` chunk precedes the `data` chunk.
+    MissingFormat,
+    /// The `fmt ` chunk describes something other than integer PCM.
+    UnsupportedFormat(u16),
+    /// The `fmt ` chunk contains inconsistent values.
+    InvalidFormat(&'static str),
+}
+
+impl fmt::Display for RiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &RiffError::Truncated => write!(f, "RIFF header truncated"),
+            &RiffError::NotWave => write!(f, "not a RIFF/WAVE header"),
+            &RiffError::MissingFormat => write!(f, "no fmt chunk"),
+            &RiffError::UnsupportedFormat(t) => write!(f, "unsupported audio format {:#06x}", t),
+            &RiffError::InvalidFormat(e) => write!(f, "invalid fmt chunk: {}", e),
+        }
+    }
+}
 
-        //let mut rdr = Cursor::new(chunk.payload);
-        let mut frames = vec![0i16; chunk.len()/2];
-        unsafe {
-            LittleEndian::read_i16_into(chunk.as_slice(), frames.as_mut_slice());
+/// Sample format of a PCM stream as described by the `fmt ` chunk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WavFormat {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub bits_per_sample: u16,
+}
+
+impl WavFormat {
+    /// Parses a RIFF/WAVE header, walking its chunks up to the `data` chunk.
+    ///
+    /// Chunks other than `fmt ` (e.g. `LIST`) are skipped. Both the plain
+    /// PCM and the extensible format are accepted.
+    pub fn parse(data: &[u8]) -> Result<WavFormat, RiffError> {
+        if data.len() < 12 {
+            return Err(RiffError::Truncated);
+        }
+        if &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+            return Err(RiffError::NotWave);
+        }
+        let mut format = None;
+        let mut data = &data[12..];
+        while data.len() >= 8 {
+            let id = &data[0..4];
+            let size = LittleEndian::read_u32(&data[4..8]) as usize;
+            data = &data[8..];
+            if id == b"data" {
+                // The data chunk is the last chunk in the header, its size
+                // is usually not known in advance.
+                return format.ok_or(RiffError::MissingFormat);
+            }
+            if data.len() < size {
+                return Err(RiffError::Truncated);
+            }
+            if id == b"fmt " {
+                format = Some(WavFormat::parse_fmt(&data[..size])?);
+            }
+            // Chunks are padded to an even size.
+            let padded = (size + 1) & !1;
+            data = &data[padded.min(data.len())..];
         }
-        //let frames = Vec::new();
-        frames
+        format.ok_or(RiffError::MissingFormat)
     }
-    fn setHeader(&self, mut header: message::CodecHeaderData) {
-        assert!(header.payload.len() >= 44);
-        //mem::transmute(s4);
-        /*let id = LittleEndian::read_u32(&header.payload[0..4]);
-        let sz = LittleEndian::read_u32(&header.payload[4..8]);
-        let wave_id = LittleEndian::read_u32(&header.payload[8..12]);*/
 
-        let data_ptr: *const RiffHeader = unsafe { mem::transmute(header.payload.as_ptr()) };
-        let data: RiffHeader = unsafe { (*data_ptr).clone() };
-        println!("{:?}", data);
+    fn parse_fmt(fmt: &[u8]) -> Result<WavFormat, RiffError> {
+        if fmt.len() < 16 {
+            return Err(RiffError::Truncated);
+        }
+        let mut audio_format = LittleEndian::read_u16(&fmt[0..2]);
+        let channels = LittleEndian::read_u16(&fmt[2..4]);
+        let sample_rate = LittleEndian::read_u32(&fmt[4..8]);
+        let byte_rate = LittleEndian::read_u32(&fmt[8..12]);
+        let block_align = LittleEndian::read_u16(&fmt[12..14]);
+        let bits_per_sample = LittleEndian::read_u16(&fmt[14..16]);
+        if audio_format == WAVE_FORMAT_EXTENSIBLE {
+            // cbSize, valid bits, channel mask, then the sub format GUID
+            // whose first two bytes are the actual format tag.
+            if fmt.len() < 40 {
+                return Err(RiffError::Truncated);
+            }
+            audio_format = LittleEndian::read_u16(&fmt[24..26]);
+        }
+        if audio_format != WAVE_FORMAT_PCM {
+            return Err(RiffError::UnsupportedFormat(audio_format));
+        }
+        if channels == 0 {
+            return Err(RiffError::InvalidFormat("no channels"));
+        }
+        if sample_rate == 0 {
+            return Err(RiffError::InvalidFormat("sample rate is 0"));
+        }
+        match bits_per_sample {
+            8 | 16 | 24 | 32 => {},
+            _ => return Err(RiffError::InvalidFormat("unsupported bits per sample")),
+        }
+        if block_align as u32 != channels as u32 * bits_per_sample as u32 / 8 {
+            return Err(RiffError::InvalidFormat("block align does not match"));
+        }
+        // Both values come from the network, the product may not fit.
+        if sample_rate.checked_mul(block_align as u32) != Some(byte_rate) {
+            return Err(RiffError::InvalidFormat("byte rate does not match"));
+        }
+        Ok(WavFormat {
+            channels: channels,
+            sample_rate: sample_rate,
+            bits_per_sample: bits_per_sample,
+        })
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample as usize / 8
+    }
+}
+
+pub struct PCMDecoder {
+    format: Option<WavFormat>,
+}
+
+impl From<RiffError> for DecoderError {
+    fn from(e: RiffError) -> DecoderError {
+        DecoderError::InvalidHeader(e.to_string())
+    }
+}
 
+impl Decoder for PCMDecoder {
+    fn decode(&mut self, chunk: &[u8]) -> Result<SampleBuffer, DecoderError> {
+        let format = match self.format {
+            Some(f) => f,
+            None => return Err(DecoderError::NoHeader),
+        };
+        let width = format.bytes_per_sample();
+        let samples = chunk.chunks(width).filter(|s| s.len() == width);
+        Ok(match format.bits_per_sample {
+            8 => SampleBuffer::S16(samples.map(|s| ((s[0] as i16) - 128) << 8).collect()),
+            16 => SampleBuffer::S16(samples.map(LittleEndian::read_i16).collect()),
+            24 => SampleBuffer::S24(samples.map(LittleEndian::read_i24).collect()),
+            _ => SampleBuffer::S32(samples.map(LittleEndian::read_i32).collect()),
+        })
+    }
+    fn set_header(&mut self, header: message::CodecHeaderData) -> Result<SampleFormat, DecoderError> {
+        self.format = None;
+        let format = WavFormat::parse(&header.payload)?;
+        self.format = Some(format);
+        Ok(SampleFormat {
+            rate: format.sample_rate,
+            channels: format.channels,
+            bits: format.bits_per_sample,
+        })
     }
     fn new() -> Self {
-        Self {}
+        PCMDecoder {
+            format: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::serialize_u32;
+
+    fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut v = id.to_vec();
+        v.extend(serialize_u32(body.len() as u32));
+        v.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            v.push(0);
+        }
+        v
+    }
+
+    fn fmt(audio_format: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut v = vec![0; 16];
+        LittleEndian::write_u16(&mut v[0..2], audio_format);
+        LittleEndian::write_u16(&mut v[2..4], channels);
+        LittleEndian::write_u32(&mut v[4..8], rate);
+        LittleEndian::write_u32(&mut v[8..12], rate.wrapping_mul(block_align as u32));
+        LittleEndian::write_u16(&mut v[12..14], block_align);
+        LittleEndian::write_u16(&mut v[14..16], bits);
+        v
+    }
+
+    fn wave(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut v = b"RIFF".to_vec();
+        v.extend(serialize_u32(0));
+        v.extend_from_slice(b"WAVE");
+        for c in chunks {
+            v.extend_from_slice(c);
+        }
+        v
     }
-    fn get_hwparams(&self, hwp: &HwParams) {
-        hwp.set_channels(2).unwrap();
-        hwp.set_rate(48000, ValueOr::Nearest).unwrap();
-        hwp.set_format(Format::s16()).unwrap();
-        hwp.set_access(Access::RWInterleaved).unwrap();
+
+    const CD: WavFormat = WavFormat { channels: 2, sample_rate: 44100, bits_per_sample: 16 };
+
+    #[test]
+    fn skips_list_chunk_before_fmt() {
+        let header = wave(&[
+            chunk(b"LIST", b"INFOISFT\x04\x00\x00\x00test"),
+            chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+            chunk(b"data", &[]),
+        ]);
+        assert_eq!(WavFormat::parse(&header), Ok(CD));
+    }
+
+    #[test]
+    fn skips_odd_sized_chunk_padding() {
+        let header = wave(&[
+            chunk(b"junk", &[1, 2, 3]),
+            chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+            chunk(b"data", &[]),
+        ]);
+        assert_eq!(WavFormat::parse(&header), Ok(CD));
+    }
+
+    #[test]
+    fn accepts_extensible_format() {
+        let mut body = fmt(WAVE_FORMAT_EXTENSIBLE, 2, 44100, 16);
+        // cbSize, valid bits, channel mask, sub format GUID.
+        body.extend_from_slice(&[22, 0, 16, 0, 3, 0, 0, 0]);
+        body.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0x10, 0, 0x80, 0, 0, 0xaa, 0, 0x38, 0x9b, 0x71]);
+        let header = wave(&[chunk(b"fmt ", &body), chunk(b"data", &[])]);
+        assert_eq!(WavFormat::parse(&header), Ok(CD));
+    }
+
+    #[test]
+    fn rejects_truncated_fmt() {
+        let body = fmt(WAVE_FORMAT_PCM, 2, 44100, 16);
+        let header = wave(&[chunk(b"fmt ", &body[..12])]);
+        assert_eq!(WavFormat::parse(&header), Err(RiffError::Truncated));
+        // The chunk announces more data than the header holds.
+        let mut header = wave(&[chunk(b"fmt ", &body)]);
+        let len = header.len();
+        header.truncate(len - 4);
+        assert_eq!(WavFormat::parse(&header), Err(RiffError::Truncated));
+    }
+
+    #[test]
+    fn rejects_data_before_fmt() {
+        let header = wave(&[
+            chunk(b"data", &[]),
+            chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 44100, 16)),
+        ]);
+        assert_eq!(WavFormat::parse(&header), Err(RiffError::MissingFormat));
+    }
+
+    #[test]
+    fn rejects_overflowing_byte_rate() {
+        let header = wave(&[chunk(b"fmt ", &fmt(WAVE_FORMAT_PCM, 2, 0xFFFFFFFF, 16)), chunk(b"data", &[])]);
+        assert_eq!(WavFormat::parse(&header), Err(RiffError::InvalidFormat("byte rate does not match")));
+        let mut decoder = PCMDecoder::new();
+        let header = message::CodecHeaderData { codec: "pcm".to_string(), payload: header };
+        match decoder.set_header(header) {
+            Err(DecoderError::InvalidHeader(_)) => {},
+            other => panic!("expected InvalidHeader, got {:?}", other),
+        }
     }
 }
@@ -0,0 +1,30 @@
+//! Snapcast client library.
+//!
+//! The `snaprust` binary is built on top of this crate. Embedders can use it
+//! to play a stream through their own `AudioOutput`, e.g. `NullOutput`
+//! without a sound card, or to register additional codecs with a
+//! `DecoderRegistry`.
+
+extern crate byteorder;
+extern crate serde;
+extern crate serde_json;
+extern crate hound;
+extern crate alsa;
+#[cfg(feature = "flac")]
+extern crate claxon;
+#[cfg(feature = "ogg")]
+extern crate lewton;
+#[cfg(feature = "opus")]
+extern crate audiopus;
+
+#[macro_use] extern crate serde_derive;
+#[macro_use] extern crate log;
+
+pub mod message;
+pub mod network_handler;
+pub mod decoder;
+pub mod time_provider;
+pub mod scheduler;
+pub mod volume;
+pub mod samples;
+pub mod output;
@@ -1,21 +1,12 @@
 extern crate clap;
-extern crate byteorder;
-extern crate serde;
-extern crate serde_json;
-extern crate hound;
-extern crate alsa;
+extern crate snaprust;
 
-use alsa::pcm::{PCM, HwParams, Format, Access, State};
-
-#[macro_use] extern crate serde_derive;
 #[macro_use] extern crate log;
 extern crate simplelog;
-use simplelog::{Config, TermLogger, WriteLogger, CombinedLogger, LogLevelFilter};
+use simplelog::{Config, TermLogger, WriteLogger, CombinedLogger, LogLevelFilter, SharedLogger};
 
 
-use std::ffi::CString;
 use clap::{Arg, App, SubCommand};
-use alsa::{Direction, ValueOr};
 use std::net::TcpStream;
 use std::io::{Write, Read};
 
@@ -25,60 +16,117 @@ use std::collections::VecDeque;
 use std::sync::Mutex;
 use std::time;
 
-mod message;
+use snaprust::message;
 use message::{SnapMessageData, TimeVal};
 
-mod network_handler;
-mod decoder;
-use decoder::Decoder;
+use snaprust::network_handler;
+use snaprust::decoder;
+use decoder::{Decoder, DecoderRegistry, SampleFormat};
+
+use snaprust::time_provider;
+use time_provider::{TimeProvider, Clock, MonotonicClock};
+
+use snaprust::scheduler;
+use scheduler::Scheduler;
+
+use snaprust::volume;
+use volume::{VolumeControl, SoftwareVolume};
+
+use snaprust::samples;
+use samples::SampleBuffer;
+
+use snaprust::output;
+use output::{AudioOutput, AlsaOutput, AlsaMixer, WavOutput, PipeOutput, NullOutput, OutputError};
+use std::sync::Arc;
 
-mod time_provider;
-use time_provider::TimeProvider;
+/// Length of the periods the audio is written to the device in.
+const PERIOD_MS: u64 = 10;
+/// Longest gap in the stream that is filled by packet loss concealment.
+const MAX_CONCEAL_MS: usize = 200;
+/// Error code reported to the server for streams that cannot be played.
+const ERROR_UNSUPPORTED_STREAM: u32 = 1;
 
 fn main() {
-    let _ = CombinedLogger::init(
-            vec![
-                TermLogger::new(LogLevelFilter::Info, Config::default()).unwrap()
-            ]
-        );
     let matches = App::new("Snaprust Client")
     .version("0.0")
     .author("pajowu <pajowu@pajowu.de>")
     .arg(Arg::with_name("pcm_list")
         .short("l")
         .long("list")
-        .help("List PCM devices"))
+        .help("Lists the PCM devices and sound cards"))
     .arg(Arg::with_name("HOST")
         .short("h")
         .long("host")
-        .help("Sets the server hostname")
-        .required(true)
+        .help("Sets the server as host or host:port, e.g. snapserver, 192.168.0.2:1704 or [::1]:1704")
+        .required_unless("pcm_list")
         .takes_value(true))
     .arg(Arg::with_name("PORT")
         .short("p")
         .long("port")
-        .help("Sets the server port")
+        .help("Sets the server port if the host has none (default: 1704)")
         .required(false)
         .takes_value(true))
     .arg(Arg::with_name("CARD")
         .short("s")
         .long("soundcard")
-        .help("Sets the soundcard index")
+        .help("Sets the soundcard by index in the list or by name, e.g. default, hw:1,0 or plughw:1")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("OUTPUT")
+        .short("o")
+        .long("output")
+        .help("Sets the output: alsa, wav:<file>, pipe (stdout), pipe:<file> or null")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("PERIOD_TIME")
+        .long("period-time")
+        .help("Sets the ALSA period time in ms (default: 10)")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("BUFFER_TIME")
+        .long("buffer-time")
+        .help("Sets the ALSA buffer time in ms (default: 60)")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("LATENCY")
+        .long("latency")
+        .help("Sets the latency (ms) of the audio path after the sound card, e.g. of an amplifier")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("MIXER")
+        .long("mixer")
+        .help("Controls the volume with an ALSA mixer element, e.g. Master, instead of in software")
+        .required(false)
+        .takes_value(true))
+    .arg(Arg::with_name("MIXER_CARD")
+        .long("mixer-card")
+        .help("Sets the card of the mixer element, e.g. hw:1 (default: the card of the sound card device)")
         .required(false)
         .takes_value(true))
     .get_matches();
 
+    let output_spec = matches.value_of("OUTPUT").unwrap_or("alsa");
+    // Keep log lines out of raw audio written to stdout.
+    let term = if output_spec == "pipe" {
+        None
+    } else {
+        TermLogger::new(LogLevelFilter::Info, Config::default())
+    };
+    let logger: Box<SharedLogger> = match term {
+        Some(term) => term,
+        // No terminal, e.g. when running headless.
+        None => WriteLogger::new(LogLevelFilter::Info, Config::default(), std::io::stderr()),
+    };
+    let _ = CombinedLogger::init(vec![logger]);
 
-    let host = matches.value_of("HOST").unwrap();
 
     if matches.is_present("pcm_list") {
-        println!("all the devices");
-        for x_ in alsa::card::Iter::new() {
-            let x = x_.unwrap();
-            debug!("{}: {} ({})", x.get_index(), x.get_name().unwrap(), x.get_longname().unwrap());
-        }
+        listDevices();
+        return;
     }
 
+    let host = matches.value_of("HOST").unwrap();
+
 
     //let pcm = PCM::open(&*CString::new("default").unwrap(), Direction::Playback, false).unwrap();
 
@@ -133,7 +181,28 @@ fn main() {
         let msg = msg.as_slice();
         stream.write_all(&msg[..]);
     }*/
-    let (mut client_conn, msg_tx, msg_rx) = network_handler::ClientConnection::start("127.0.0.1:1704");
+    let clock = Arc::new(MonotonicClock::new());
+    let default_port = match matches.value_of("PORT").map_or(Ok(network_handler::DEFAULT_PORT), |p| p.parse::<u16>()) {
+        Ok(p) => p,
+        Err(e) => {
+            error!("Invalid port: {}", e);
+            return;
+        }
+    };
+    let (host, port) = match network_handler::split_host_port(host, default_port) {
+        Ok(s) => s,
+        Err(e) => {
+            error!("Invalid server {}", e);
+            return;
+        }
+    };
+    let (mut client_conn, msg_tx, msg_rx) = match network_handler::ClientConnection::start(&host, port, clock.clone()) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Cannot connect to {} port {}: {}", host, port, e);
+            return;
+        }
+    };
 
     let t = thread::spawn(move || {
         client_conn.worker();
@@ -177,104 +246,288 @@ fn main() {
         msg_tx.send(time_msg);
     }
 
-    let pcm = PCM::open(&*CString::new("default").unwrap(), Direction::Playback, false).unwrap();
-    let hwp = HwParams::any(&pcm).unwrap();
+    let extra_latency_ms = match matches.value_of("LATENCY").unwrap_or("0").parse::<i64>() {
+        Ok(l) => l,
+        Err(e) => {
+            error!("Invalid latency: {}", e);
+            return;
+        }
+    };
+    let card = matches.value_of("CARD").unwrap_or("default");
+    let period_ms = matches.value_of("PERIOD_TIME").map_or(Ok(output::DEFAULT_PERIOD_MS), |v| v.parse::<u32>());
+    let buffer_ms = matches.value_of("BUFFER_TIME").map_or(Ok(output::DEFAULT_BUFFER_MS), |v| v.parse::<u32>());
+    let (period_ms, buffer_ms) = match (period_ms, buffer_ms) {
+        (Ok(p), Ok(b)) if p > 0 && b >= 2 * p => (p, b),
+        _ => {
+            error!("Invalid period or buffer time, the buffer must hold at least two periods");
+            return;
+        }
+    };
+    let mut output = match createOutput(output_spec, card, period_ms, buffer_ms, clock.clone()) {
+        Ok(o) => o,
+        Err(e) => {
+            error!("{}", e);
+            return;
+        }
+    };
 
+    let codecs = DecoderRegistry::new();
+    info!("Supported codecs: {}", codecs.codecs().join(", "));
     let mut decoder: Option<Box<Decoder>> = None;
 
-    let mut time_provider = TimeProvider::new();
+    let mut time_provider = TimeProvider::new(clock);
 
-    let mut buffer_queue: Vec<(usize, Vec<i16>)> = Vec::new();
-
-
-    loop {
-        while let Ok(msg) = msg_rx.try_recv() {
+    let mut scheduler: Option<Scheduler> = None;
+    // Underruns of the output seen so far.
+    let mut underruns = 0;
+    let mut settings: Option<message::ServerSettingsData> = None;
+    let mixer_card = match matches.value_of("MIXER_CARD") {
+        Some(c) => c.to_string(),
+        None => output::select_device(card).map(|d| output::mixer_card(&d)).unwrap_or("default".to_string()),
+    };
+    let mut volume = createVolume(matches.value_of("MIXER"), &mixer_card);
+
+    'playback: loop {
+        loop {
+            let msg = match msg_rx.try_recv() {
+                Ok(msg) => msg,
+                Err(mpsc::TryRecvError::Empty) => break,
+                Err(mpsc::TryRecvError::Disconnected) => break 'playback,
+            };
             debug!("Got message: {:?}", msg);
             match msg.type_ {
                 message::MessageType::Base(_) => {},
                 message::MessageType::CodecHeader(d) => {
-                    decoder = handleCodecHeader(d, &hwp);
-                    pcm.hw_params(&hwp).unwrap();
+                    decoder = None;
+                    scheduler = None;
+                    output.close();
+                    if let Some((dec, format)) = handleCodecHeader(&codecs, d, msg.id, &msg_tx) {
+                        match output.open(&format) {
+                            Ok(f) => {
+                                let mut s = Scheduler::new(f.rate, f.channels as usize, f.sample_type);
+                                s.set_extra_latency_ms(extra_latency_ms);
+                                if let Some(ref settings) = settings {
+                                    applyServerSetting(settings, &mut s);
+                                }
+                                decoder = Some(dec);
+                                scheduler = Some(s);
+                                underruns = 0;
+                            },
+                            Err(e) => error!("{}", e)
+                        }
+                    }
                 },
                 message::MessageType::WireChunk(d) => {
-                    let chunk = handleWireChunk(&decoder, d);
-                    match chunk {
-                        Some(c) => buffer_queue.push(c),
+                    // The decoder must conceal the gap from its state before
+                    // it, i.e. before decoding the chunk after it.
+                    if let Some(s) = scheduler.as_mut() {
+                        concealGap(&mut decoder, s, d.timestamp.to_micros());
+                    }
+                    let chunk = handleWireChunk(&mut decoder, d);
+                    match (chunk, scheduler.as_mut()) {
+                        (Some(c), Some(s)) => s.add_chunk(c.0, c.1),
                         _ => {}
                     }
                 },
-                message::MessageType::ServerSettings(d) => handleServerSetting(d),
-                message::MessageType::Time(d) => handleTime(d, &mut time_provider),
+                message::MessageType::ServerSettings(d) => {
+                    handleServerSetting(&d, &mut scheduler, volume.as_mut());
+                    settings = Some(d);
+                },
+                message::MessageType::Time(ref d) => handleTime(d, &msg.sent, &msg.recieved, &mut time_provider),
                 message::MessageType::Hello(_) => {},
+                message::MessageType::StreamTags(d) => info!("Stream tags: {}", d.tags),
+                message::MessageType::ClientInfo(_) => {},
+                message::MessageType::Error(d) => error!("Server error {}: {} ({})", d.code, d.error, d.message),
+                message::MessageType::Unknown { type_id, .. } => debug!("Ignoring message of unknown type {}", type_id),
             };
         }
 
-        buffer_queue.sort_by(|a, b| a.0.cmp(&b.0));
-        let server_time = time_provider.get_server_time();
-
-        //buffer_queue = buffer_queue.into_iter().filter(|x| (x.0 as usize) > server_time).collect();
-        //(server now - rec time: some positive value) - buffer (e.g. 1000ms) + time to DAC
-        let io = pcm.io_i16().unwrap();
-        let mut t_v = Vec::new();
-        let mut buf_q: Vec<(usize, Vec<i16>)> = Vec::new();
-        for e in buffer_queue {
-            let age = (server_time as isize - e.0 as isize) - 1000 + 150;
-            if 0 <= age && age <= 100 {
-                t_v.extend(e.1);
-            } else if age > 0 {
-                println!("{}", age);
-                buf_q.push(e)
-            } else {
-                println!("{}", e.0);
+        let scheduler = match scheduler.as_mut() {
+            Some(s) => s,
+            None => {
+                thread::sleep(time::Duration::from_millis(PERIOD_MS));
+                continue;
+            }
+        };
+
+        // Audio written now is heard once everything already queued in the
+        // device has been played.
+        let delay = match output.delay() {
+            Ok(delay) => delay,
+            Err(e) => {
+                debug!("Cannot get output delay: {}", e);
+                0
+            }
+        };
+        let diff_to_server = time_provider.get_diff_to_server();
+        debug!("Clock offset: {}us, drift: {:.2}ppm", diff_to_server, time_provider.get_drift());
+        output.set_diff_to_server(diff_to_server);
+
+        let frames = scheduler.rate() as usize * PERIOD_MS as usize / 1000;
+        let mut period = SampleBuffer::silence(scheduler.sample_type(), frames * scheduler.channels());
+        // Until the server time is known, chunk timestamps cannot be mapped
+        // to the local clock.
+        if time_provider.is_synced() {
+            if let Some((frame, timestamp)) = scheduler.fill(time_provider.get_server_time(), delay, &mut period) {
+                output.set_source_time(frame, timestamp);
             }
         }
-        info!("buf: {:?}", t_v.len());
-        while let Err(e) = io.writei(t_v.as_slice()) {
-            info!("write to pipe got error {:?}, retry", e.code());
-            pcm.recover(e.code(), true);
-        }
-        //if pcm.state() != State::Running { pcm.start().unwrap() };
-        buffer_queue = buf_q;
+        volume.apply(&mut period, scheduler.channels());
 
-        thread::sleep(time::Duration::from_millis(1000));
+        if let Err(e) = output.write(&period) {
+            error!("{}", e);
+            thread::sleep(time::Duration::from_millis(PERIOD_MS));
+        }
+        if output.underruns() > underruns {
+            underruns = output.underruns();
+            warn!("Output underrun ({} since the stream started), resyncing", underruns);
+            scheduler.resync();
+        }
     }
 
+    // The connection to the server is gone, play what has been written.
+    if let Err(e) = output.drain() {
+        warn!("{}", e);
+    }
+    output.close();
     t.join();
 
 }
 
-fn handleCodecHeader(data: message::CodecHeaderData, hwp: &HwParams) -> Option<Box<Decoder>> {
-    let decoder: Box<Decoder> = match data.codec.as_str() {
-        "pcm" => Box::new(decoder::PCMDecoder),
-        _ => Box::new(decoder::DummyDecoder)
+/// Creates the output selected with `--output`, using sound card `card`
+/// with the given period and buffer times for ALSA.
+fn createOutput(spec: &str, card: &str, period_ms: u32, buffer_ms: u32, clock: Arc<Clock>)
+                -> Result<Box<AudioOutput>, OutputError> {
+    let (kind, arg) = match spec.find(':') {
+        Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+        None => (spec, None)
     };
-    decoder.setHeader(data);
-    decoder.get_hwparams(hwp);
-    Some(decoder)
+    match (kind, arg) {
+        ("alsa", None) => Ok(Box::new(AlsaOutput::new(&output::select_device(card)?, period_ms, buffer_ms))),
+        ("wav", Some(path)) => Ok(Box::new(WavOutput::new(path))),
+        ("pipe", None) => Ok(Box::new(PipeOutput::stdout())),
+        ("pipe", Some(path)) => Ok(Box::new(PipeOutput::new(path))),
+        ("null", None) => Ok(Box::new(NullOutput::new(clock))),
+        _ => Err(OutputError::Open(format!("unknown output {}", spec)))
+    }
 }
-fn handleWireChunk(decoder: &Option<Box<Decoder>>, data: message::WireChunkData) -> Option<(usize,Vec<i16>)> {
+/// Creates the volume control, the mixer element `mixer` of `card` if set
+/// and usable, software volume otherwise.
+fn createVolume(mixer: Option<&str>, card: &str) -> Box<VolumeControl> {
+    if let Some(element) = mixer {
+        match AlsaMixer::new(card, element) {
+            Ok(m) => {
+                info!("Using mixer element {} on {} for volume", element, card);
+                return Box::new(m);
+            },
+            Err(e) => warn!("{}, using software volume", e)
+        }
+    }
+    Box::new(SoftwareVolume::new())
+}
+/// Prints the PCM devices and the sound cards with what they support.
+fn listDevices() {
+    match output::pcm_devices() {
+        Ok(devices) => for (i, d) in devices.iter().enumerate() {
+            println!("{}: {}", i, d.name);
+            for line in d.description.lines() {
+                println!("    {}", line);
+            }
+        },
+        Err(e) => error!("Cannot list PCM devices: {}", e)
+    }
+    let cards = match output::sound_cards() {
+        Ok(cards) => cards,
+        Err(e) => {
+            error!("Cannot list sound cards: {}", e);
+            return;
+        }
+    };
+    for card in cards {
+        println!();
+        println!("Card {}: {} ({})", card.index, card.name, card.longname);
+        for d in card.devices {
+            print!("  hw:{},{},{}", d.card, d.device, d.subdevice);
+            if d.busy {
+                println!(" (busy)");
+                continue;
+            }
+            println!(" {} [{}]", d.name, d.subdevice_name);
+            let rates: Vec<String> = d.rates.iter().map(|r| r.to_string()).collect();
+            let types: Vec<String> = d.sample_types.iter().map(|t| format!("{:?}", t)).collect();
+            println!("    rates: {}", rates.join(", "));
+            println!("    formats: {}", types.join(", "));
+        }
+    }
+}
+fn handleCodecHeader(codecs: &DecoderRegistry, data: message::CodecHeaderData, id: u16,
+                     msg_tx: &mpsc::Sender<message::Message>) -> Option<(Box<Decoder>, SampleFormat)> {
+    let codec = data.codec.clone();
+    let result = codecs.create(&codec).and_then(|mut decoder| {
+        let format = decoder.set_header(data)?;
+        Ok((decoder, format))
+    });
+    match result {
+        Ok((decoder, format)) => {
+            info!("{} stream: {} Hz, {} channels, {} bit", codec, format.rate, format.channels, format.bits);
+            Some((decoder, format))
+        },
+        Err(e) => {
+            error!("Cannot play {} stream: {}", codec, e);
+            let error_msg = message::Message {
+                type_: message::MessageType::Error(message::ErrorData {
+                    code: ERROR_UNSUPPORTED_STREAM,
+                    error: e.to_string(),
+                    message: format!("Cannot play {} stream", codec)
+                }),
+                id: 0,
+                refers_to: id,
+                recieved: message::TimeVal::new(),
+                sent: message::TimeVal::new()
+            };
+            let _ = msg_tx.send(error_msg);
+            None
+        }
+    }
+}
+fn handleWireChunk(decoder: &mut Option<Box<Decoder>>, data: message::WireChunkData) -> Option<(i64,SampleBuffer)> {
     match decoder {
-        &Some(ref d) => {
-            let time = (data.timestamp.sec as usize)*1000 + (data.timestamp.usec / 1000) as usize;
-            Some((time, d.decode(data.payload)))
+        &mut Some(ref mut d) => {
+            match d.decode(&data.payload) {
+                Ok(samples) => Some((data.timestamp.to_micros(), samples)),
+                Err(e) => {
+                    warn!("Dropping chunk at {}us: {}", data.timestamp.to_micros(), e);
+                    None
+                }
+            }
         },
-        &None => None
+        &mut None => None
     }
-    /*if decoder.is_some() {
-        let decoder = decoder.unwrap();
-        let new_chunk = decoder.decode(data);
-        /*let buffer = SamplesBuffer::new(1, 44100, new_chunk);*/
-        sink.append(new_chunk);
-        Some(decoder)
-    } else {
-        decoder
-    }*/
 }
-fn handleServerSetting(data: message::ServerSettingsData) {
-
+/// Lets the decoder fill in for chunks lost before the chunk at `timestamp`.
+fn concealGap(decoder: &mut Option<Box<Decoder>>, scheduler: &mut Scheduler, timestamp: i64) {
+    if let (&mut Some(ref mut d), Some((start, frames))) = (decoder, scheduler.missing_before(timestamp)) {
+        debug!("{} frames missing before chunk at {}us", frames, timestamp);
+        let max = scheduler.rate() as usize * MAX_CONCEAL_MS / 1000;
+        let samples = d.conceal(frames.min(max));
+        if !samples.is_empty() {
+            scheduler.add_chunk(start, samples);
+        }
+    }
 }
-fn handleTime(data: message::TimeData, time_provider: &mut TimeProvider) {
-
-    time_provider.add_time(data.latency);
-
+fn handleServerSetting(data: &message::ServerSettingsData, scheduler: &mut Option<Scheduler>, volume: &mut VolumeControl) {
+    info!("Server settings: volume {}, muted {}, latency {}ms, buffer {}ms",
+        data.volume, data.muted, data.latency, data.buffer_ms);
+    volume.set(data.volume, data.muted);
+    if let Some(ref mut s) = *scheduler {
+        applyServerSetting(data, s);
+    }
+}
+fn applyServerSetting(data: &message::ServerSettingsData, scheduler: &mut Scheduler) {
+    scheduler.set_buffer_ms(data.buffer_ms as i64);
+    scheduler.set_latency_ms(data.latency as i64);
+}
+fn handleTime(data: &message::TimeData, sent: &TimeVal, recieved: &TimeVal, time_provider: &mut TimeProvider) {
+    let s2c = TimeVal::from_micros(recieved.to_micros() - sent.to_micros());
+    time_provider.add_time(data.latency.clone(), s2c, recieved.clone());
 }
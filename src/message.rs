@@ -3,11 +3,13 @@ use serde_json;
 use serde_derive;
 
 use std::time::{SystemTime, UNIX_EPOCH};
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt};
-use std::net::TcpStream;
-use std::io::{Write, Read, Error};
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Error, ErrorKind};
+use std::mem;
 use std::str;
+use std::fmt;
 use std::fmt::Debug;
+use std::error;
 use std::marker::Send;
 
 pub fn serialize_u16(val: u16) -> Vec<u8> {
@@ -29,6 +31,14 @@ pub fn serialize_i32(val: i32) -> Vec<u8> {
 }
 
 
+/// Serializes `val` prefixed with its length as u32, as used for strings,
+/// JSON documents and binary payloads on the wire.
+pub fn serialize_bytes(val: &[u8]) -> Vec<u8> {
+    let mut buf = serialize_u32(val.len() as u32);
+    buf.extend_from_slice(val);
+    return buf;
+}
+
 pub fn deserialize_u16(buf: &[u8]) -> u16 {
     LittleEndian::read_u16(buf)
 }
@@ -41,12 +51,91 @@ pub fn deserialize_i32(buf: &[u8]) -> i32 {
     LittleEndian::read_i32(buf)
 }
 
+/// Splits `n` bytes off the front of `data`.
+fn take(data: &[u8], n: usize) -> Result<(&[u8], &[u8]), ProtocolError> {
+    if data.len() < n {
+        return Err(ProtocolError::Truncated);
+    }
+    Ok(data.split_at(n))
+}
+
+/// Splits a u32 length-prefixed field (see `serialize_bytes`) off the front
+/// of `data`.
+fn take_bytes(data: &[u8]) -> Result<(&[u8], &[u8]), ProtocolError> {
+    let (len, data) = take(data, 4)?;
+    let len = deserialize_u32(len) as usize;
+    if data.len() < len {
+        return Err(ProtocolError::BadLength);
+    }
+    Ok(data.split_at(len))
+}
+
+/// Fails with `BadLength` if a body did not consume all of its data.
+fn expect_end(data: &[u8]) -> Result<(), ProtocolError> {
+    if data.len() != 0 {
+        return Err(ProtocolError::BadLength);
+    }
+    Ok(())
+}
+
+/// Errors produced while decoding data received from the network.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The data ended before a fixed-size field could be read.
+    Truncated,
+    /// A length field does not match the amount of data available.
+    BadLength,
+    /// A string field is not valid UTF-8.
+    BadUtf8(str::Utf8Error),
+    /// A JSON body could not be parsed.
+    BadJson(serde_json::Error),
+    /// A message announced a body of this many bytes, more than
+    /// `MAX_MESSAGE_SIZE`.
+    TooLarge(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ProtocolError::Truncated => write!(f, "message truncated"),
+            &ProtocolError::BadLength => write!(f, "length field does not match data"),
+            &ProtocolError::BadUtf8(ref e) => write!(f, "invalid UTF-8: {}", e),
+            &ProtocolError::BadJson(ref e) => write!(f, "invalid JSON: {}", e),
+            &ProtocolError::TooLarge(size) => write!(f, "message of {} bytes is too large", size),
+        }
+    }
+}
+
+impl error::Error for ProtocolError {
+    fn description(&self) -> &str {
+        match self {
+            &ProtocolError::Truncated => "message truncated",
+            &ProtocolError::BadLength => "length field does not match data",
+            &ProtocolError::BadUtf8(_) => "invalid UTF-8",
+            &ProtocolError::BadJson(_) => "invalid JSON",
+            &ProtocolError::TooLarge(_) => "message too large",
+        }
+    }
+}
+
+impl From<str::Utf8Error> for ProtocolError {
+    fn from(e: str::Utf8Error) -> ProtocolError {
+        ProtocolError::BadUtf8(e)
+    }
+}
+
+impl From<serde_json::Error> for ProtocolError {
+    fn from(e: serde_json::Error) -> ProtocolError {
+        ProtocolError::BadJson(e)
+    }
+}
+
 pub trait SnapMessageData: Debug + Send {
     fn serialize_vec(&self) -> Vec<u8>;
-    fn deserialize(data: &[u8]) -> Self where Self: Sized;
+    fn deserialize(data: &[u8]) -> Result<Self, ProtocolError> where Self: Sized;
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MessageType {
     Base(BaseData),
     CodecHeader(CodecHeaderData),
@@ -54,6 +143,12 @@ pub enum MessageType {
     ServerSettings(ServerSettingsData),
     Time(TimeData),
     Hello(HelloData),
+    StreamTags(StreamTagsData),
+    ClientInfo(ClientInfoData),
+    Error(ErrorData),
+    /// A message type this client does not understand, kept verbatim so it
+    /// can be forwarded or re-serialized unchanged.
+    Unknown { type_id: u16, raw: Vec<u8> },
 }
 
 /*impl From<u8> for MessageType {
@@ -78,7 +173,11 @@ impl MessageType {
             &MessageType::WireChunk(_) => 2,
             &MessageType::ServerSettings(_) => 3,
             &MessageType::Time(_) => 4,
-            &MessageType::Hello(_) => 5
+            &MessageType::Hello(_) => 5,
+            &MessageType::StreamTags(_) => 6,
+            &MessageType::ClientInfo(_) => 7,
+            &MessageType::Error(_) => 8,
+            &MessageType::Unknown { type_id, .. } => type_id
         }
     }
 }
@@ -92,6 +191,10 @@ impl MessageType {
             &MessageType::ServerSettings(ref e) => e,
             &MessageType::Time(ref e) => e,
             &MessageType::Hello(ref e) => e,
+            &MessageType::StreamTags(ref e) => e,
+            &MessageType::ClientInfo(ref e) => e,
+            &MessageType::Error(ref e) => e,
+            &MessageType::Unknown { ref raw, .. } => return raw.clone(),
         };
         t.serialize_vec()
     }
@@ -112,7 +215,7 @@ impl MessageType {
 }*/
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TimeVal {
     pub sec: isize,
     pub usec: isize
@@ -126,6 +229,27 @@ impl TimeVal {
         return tv_vec
     }
 
+    fn deserialize(data: &[u8]) -> Result<TimeVal, ProtocolError> {
+        let (data, _) = take(data, 8)?;
+        Ok(TimeVal {
+            sec: deserialize_i32(&data[0..4]) as isize,
+            usec: deserialize_i32(&data[4..8]) as isize
+        })
+    }
+
+    /// Builds a `TimeVal` from signed microseconds.
+    pub fn from_micros(us: i64) -> TimeVal {
+        TimeVal {
+            sec: us.div_euclid(1_000_000) as isize,
+            usec: us.rem_euclid(1_000_000) as isize
+        }
+    }
+
+    /// Returns this time value in signed microseconds.
+    pub fn to_micros(&self) -> i64 {
+        (self.sec as i64) * 1_000_000 + self.usec as i64
+    }
+
     pub fn new() -> TimeVal {
         let start = SystemTime::now();
         let since_the_epoch = start.duration_since(UNIX_EPOCH)
@@ -138,7 +262,7 @@ impl TimeVal {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Message {
     pub type_: MessageType,
     pub id: u16,
@@ -148,8 +272,11 @@ pub struct Message {
 }
 
 const BASE_MESSAGE_SIZE: usize = 26;
+/// Largest message body accepted from the peer. Real messages are far
+/// smaller; a bigger size field means the stream is corrupt.
+pub const MAX_MESSAGE_SIZE: usize = 1 << 20;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BaseData {
 }
 
@@ -157,8 +284,8 @@ impl SnapMessageData for BaseData {
     fn serialize_vec(&self) -> Vec<u8> {
         Vec::new()
     }
-    fn deserialize(data: &[u8]) -> Self {
-        BaseData {}
+    fn deserialize(_data: &[u8]) -> Result<Self, ProtocolError> {
+        Ok(BaseData {})
     }
 }
 
@@ -178,47 +305,109 @@ impl Message {
         return msg_vec
     }
 
-    pub fn deserialize(data: Vec<u8>) {
-
-    }
-
-    pub fn deserialize_from_socket(mut socket: &TcpStream) -> Result<Message, Error> {
-        let type_ = socket.read_u16::<LittleEndian>()?;
+    /// Parses a complete message (base header plus payload) from `data`.
+    pub fn deserialize(data: &[u8]) -> Result<Message, ProtocolError> {
+        if data.len() < BASE_MESSAGE_SIZE {
+            return Err(ProtocolError::Truncated);
+        }
+        let type_ = deserialize_u16(&data[0..2]);
         debug!("Message Type: {:?}", type_);
-        let id = socket.read_u16::<LittleEndian>()?;
+        let id = deserialize_u16(&data[2..4]);
         debug!("ID: {:?}", id);
-        let refers_to = try!(socket.read_u16::<LittleEndian>());
+        let refers_to = deserialize_u16(&data[4..6]);
         debug!("RefersTo: {:?}", refers_to);
-        let recv_sec = socket.read_i32::<LittleEndian>()?;
-        let recv_usec = socket.read_i32::<LittleEndian>()?;
-        debug!("Recieved: ({:?}, {:?})", recv_sec, recv_usec);
-        let sent_sec = socket.read_i32::<LittleEndian>()?;
-        let sent_usec = socket.read_i32::<LittleEndian>()?;
-        debug!("Sent: ({:?}, {:?})", sent_sec, sent_usec);
-        let data_size = socket.read_u32::<LittleEndian>()?;
-        debug!("Size: {:?}", data_size);
-        let mut buf = vec![0; data_size as usize];
-        let data = socket.read(&mut buf);
+        let recieved = TimeVal::deserialize(&data[6..14])?;
+        debug!("Recieved: {:?}", recieved);
+        let sent = TimeVal::deserialize(&data[14..22])?;
+        debug!("Sent: {:?}", sent);
+        let (buf, rest) = take_bytes(&data[22..])?;
+        debug!("Size: {:?}", buf.len());
+        expect_end(rest)?;
         let type_: MessageType = match type_ {
-            1 => MessageType::CodecHeader(CodecHeaderData::deserialize(&buf)),
-            2 => MessageType::WireChunk(WireChunkData::deserialize(&buf)),
-            3 => MessageType::ServerSettings(ServerSettingsData::deserialize(&buf)),
-            4 => MessageType::Time(TimeData::deserialize(&buf)),
-            5 => MessageType::Hello(HelloData::deserialize(&buf)),
-            _ => MessageType::Base(BaseData {}),
+            0 => MessageType::Base(BaseData::deserialize(buf)?),
+            1 => MessageType::CodecHeader(CodecHeaderData::deserialize(buf)?),
+            2 => MessageType::WireChunk(WireChunkData::deserialize(buf)?),
+            3 => MessageType::ServerSettings(ServerSettingsData::deserialize(buf)?),
+            4 => MessageType::Time(TimeData::deserialize(buf)?),
+            5 => MessageType::Hello(HelloData::deserialize(buf)?),
+            6 => MessageType::StreamTags(StreamTagsData::deserialize(buf)?),
+            7 => MessageType::ClientInfo(ClientInfoData::deserialize(buf)?),
+            8 => MessageType::Error(ErrorData::deserialize(buf)?),
+            t => MessageType::Unknown { type_id: t, raw: buf.to_vec() },
         };
         Ok(Message {
             type_: type_,
             id: id,
             refers_to: refers_to,
-            recieved: TimeVal { sec: recv_sec as isize, usec: recv_usec as isize },
-            sent: TimeVal { sec: sent_sec as isize, usec: sent_usec as isize },
+            recieved: recieved,
+            sent: sent,
         })
     }
 }
 
+/// Incremental framing decoder for the snapcast wire protocol.
+///
+/// Bytes are buffered until a complete base header and its `size` payload
+/// bytes are available, so short reads and `WouldBlock` in the middle of a
+/// message never desynchronize the stream.
+#[derive(Debug, Default)]
+pub struct MessageDecoder {
+    buf: Vec<u8>,
+}
+
+impl MessageDecoder {
+    pub fn new() -> MessageDecoder {
+        MessageDecoder { buf: Vec::new() }
+    }
+
+    /// Appends raw bytes received from the peer.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+    /// Performs a single read from `reader` into the internal buffer.
+    ///
+    /// Returns the number of bytes read, `Ok(0)` meaning end of stream.
+    /// `WouldBlock` from non-blocking readers is passed through unchanged.
+    pub fn read_from<R: Read>(&mut self, reader: &mut R) -> Result<usize, Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            match reader.read(&mut chunk) {
+                Ok(n) => {
+                    self.feed(&chunk[..n]);
+                    return Ok(n);
+                },
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the next complete message, or `None` if more data is needed.
+    ///
+    /// A message that is framed correctly but fails to parse is consumed and
+    /// reported as an error, so decoding can continue with the next one.
+    /// A size field above `MAX_MESSAGE_SIZE` yields `TooLarge` every time,
+    /// as the stream cannot be resynchronized; the connection must be dropped.
+    pub fn next_message(&mut self) -> Option<Result<Message, ProtocolError>> {
+        if self.buf.len() < BASE_MESSAGE_SIZE {
+            return None;
+        }
+        let size = deserialize_u32(&self.buf[22..BASE_MESSAGE_SIZE]) as usize;
+        if size > MAX_MESSAGE_SIZE {
+            return Some(Err(ProtocolError::TooLarge(size)));
+        }
+        let frame_len = BASE_MESSAGE_SIZE + size;
+        if self.buf.len() < frame_len {
+            return None;
+        }
+        let rest = self.buf.split_off(frame_len);
+        let frame = mem::replace(&mut self.buf, rest);
+        Some(Message::deserialize(&frame))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct HelloData {
     #[serde(rename = "MAC")]
     pub mac: String,
@@ -240,19 +429,16 @@ pub struct HelloData {
 
 impl SnapMessageData for HelloData {
     fn serialize_vec(&self) -> Vec<u8> {
-        let mut v = Vec::new();
         let s: String = serde_json::to_string(&self).unwrap();
-        let s = s.as_bytes().to_vec();
-        v.extend(serialize_u32(s.len() as u32));
-        v.extend(s);
-        v
+        serialize_bytes(s.as_bytes())
     }
-    fn deserialize(data: &[u8]) -> HelloData {
-        let s: HelloData = serde_json::from_slice(&data[4..]).unwrap();
-        s
+    fn deserialize(data: &[u8]) -> Result<HelloData, ProtocolError> {
+        let (json, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(serde_json::from_slice(json)?)
     }
 }
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ServerSettingsData {
     pub muted: bool,
     #[serde(rename = "bufferMs")]
@@ -263,39 +449,35 @@ pub struct ServerSettingsData {
 
 impl SnapMessageData for ServerSettingsData {
     fn serialize_vec(&self) -> Vec<u8> {
-        Vec::new()
+        let s: String = serde_json::to_string(&self).unwrap();
+        serialize_bytes(s.as_bytes())
     }
-    fn deserialize(data: &[u8]) -> ServerSettingsData {
-        let s = serde_json::from_slice(&data[4..]).unwrap();
-        s
-
+    fn deserialize(data: &[u8]) -> Result<ServerSettingsData, ProtocolError> {
+        let (json, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(serde_json::from_slice(json)?)
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct TimeData {
     pub latency: TimeVal
 }
 
 impl SnapMessageData for TimeData {
     fn serialize_vec(&self) -> Vec<u8> {
-        let v = Vec::new();
-        v
+        self.latency.serialize()
     }
-    fn deserialize(data: &[u8]) -> Self {
-        let sec = LittleEndian::read_i32(&data[..4]);
-        let usec = LittleEndian::read_i32(&data[4..]);
-        TimeData {
-            latency: TimeVal {
-                sec: sec as isize,
-                usec: usec as isize
-            }
-        }
-
+    fn deserialize(data: &[u8]) -> Result<Self, ProtocolError> {
+        let (latency, data) = take(data, 8)?;
+        expect_end(data)?;
+        Ok(TimeData {
+            latency: TimeVal::deserialize(latency)?
+        })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct CodecHeaderData {
     pub codec: String,
     pub payload: Vec<u8>
@@ -303,54 +485,251 @@ pub struct CodecHeaderData {
 
 impl SnapMessageData for CodecHeaderData {
     fn serialize_vec(&self) -> Vec<u8> {
-        let v = Vec::new();
+        let mut v = serialize_bytes(self.codec.as_bytes());
+        v.extend(serialize_bytes(&self.payload));
         v
     }
 
-    fn deserialize(data: &[u8]) -> CodecHeaderData {
-        let mut data = Vec::from(data);
-        let (codec_len_, data) = data.split_at(4);
-        let codec_len = LittleEndian::read_u32(codec_len_);
-        let (codec_, data) = data.split_at(codec_len as usize);
-        let codec = String::from_utf8(codec_.to_vec()).unwrap();
-        let (payload_len_, data) = data.split_at(4);
-        let payload_len = LittleEndian::read_u32(payload_len_);
-        let (payload, data) = data.split_at(payload_len as usize);
-        assert!(data.len() == 0);
-        CodecHeaderData {
-            codec: codec,
+    fn deserialize(data: &[u8]) -> Result<CodecHeaderData, ProtocolError> {
+        let (codec, data) = take_bytes(data)?;
+        let codec = str::from_utf8(codec)?;
+        let (payload, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(CodecHeaderData {
+            codec: codec.to_string(),
             payload: payload.to_vec()
-        }
+        })
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct WireChunkData {
     pub timestamp: TimeVal,
     pub payload: Vec<u8>
 }
 impl SnapMessageData for WireChunkData {
     fn serialize_vec(&self) -> Vec<u8> {
-        let v = Vec::new();
+        let mut v = self.timestamp.serialize();
+        v.extend(serialize_bytes(&self.payload));
         v
     }
 
-    fn deserialize(data: &[u8]) -> WireChunkData {
-        let mut data = Vec::from(data);
-        let (sec_, data) = data.split_at(4);
-        let (usec_, data) = data.split_at(4);
-        let (payload_len_, data) = data.split_at(4);
-        let sec = LittleEndian::read_i32(sec_);
-        let usec = LittleEndian::read_i32(usec_);
-        let payload_len = LittleEndian::read_i32(payload_len_);
-        let (payload, data) = data.split_at(payload_len as usize);
-        assert!(data.len() == 0);
-        WireChunkData {
+    fn deserialize(data: &[u8]) -> Result<WireChunkData, ProtocolError> {
+        let (timestamp, data) = take(data, 8)?;
+        let (payload, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(WireChunkData {
             payload: payload.to_vec(),
-            timestamp: TimeVal {
-                sec: sec as isize,
-                usec: usec as isize
+            timestamp: TimeVal::deserialize(timestamp)?
+        })
+    }
+}
+
+/// Metadata of the currently playing stream, as sent by the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamTagsData {
+    pub tags: serde_json::Value
+}
+
+impl SnapMessageData for StreamTagsData {
+    fn serialize_vec(&self) -> Vec<u8> {
+        let s: String = serde_json::to_string(&self.tags).unwrap();
+        serialize_bytes(s.as_bytes())
+    }
+    fn deserialize(data: &[u8]) -> Result<StreamTagsData, ProtocolError> {
+        let (json, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(StreamTagsData {
+            tags: serde_json::from_slice(json)?
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfoData {
+    pub volume: u16,
+    pub muted: bool
+}
+
+impl SnapMessageData for ClientInfoData {
+    fn serialize_vec(&self) -> Vec<u8> {
+        let s: String = serde_json::to_string(&self).unwrap();
+        serialize_bytes(s.as_bytes())
+    }
+    fn deserialize(data: &[u8]) -> Result<ClientInfoData, ProtocolError> {
+        let (json, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(serde_json::from_slice(json)?)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorData {
+    pub code: u32,
+    pub error: String,
+    pub message: String
+}
+
+impl SnapMessageData for ErrorData {
+    fn serialize_vec(&self) -> Vec<u8> {
+        let mut v = serialize_u32(self.code);
+        v.extend(serialize_bytes(self.error.as_bytes()));
+        v.extend(serialize_bytes(self.message.as_bytes()));
+        v
+    }
+
+    fn deserialize(data: &[u8]) -> Result<ErrorData, ProtocolError> {
+        let (code, data) = take(data, 4)?;
+        let (error, data) = take_bytes(data)?;
+        let (message, data) = take_bytes(data)?;
+        expect_end(data)?;
+        Ok(ErrorData {
+            code: deserialize_u32(code),
+            error: str::from_utf8(error)?.to_string(),
+            message: str::from_utf8(message)?.to_string()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    fn message(type_: MessageType) -> Message {
+        Message {
+            type_: type_,
+            id: 7,
+            refers_to: 3,
+            recieved: TimeVal { sec: 12, usec: 345 },
+            sent: TimeVal { sec: 67, usec: 890 },
+        }
+    }
+
+    /// Hands out `data` one byte per read, failing with `WouldBlock` once
+    /// before the byte at each offset in `blocks`.
+    struct SlowReader {
+        data: Vec<u8>,
+        pos: usize,
+        blocks: Vec<usize>,
+    }
+
+    impl Read for SlowReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.blocks.first() == Some(&self.pos) {
+                self.blocks.remove(0);
+                return Err(Error::new(ErrorKind::WouldBlock, "no data"));
+            }
+            if self.pos == self.data.len() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.data[self.pos];
+            self.pos += 1;
+            Ok(1)
+        }
+    }
+
+    /// Reads `reader` to the end, collecting all decoded messages.
+    fn decode_all(reader: &mut SlowReader) -> Vec<Message> {
+        let mut decoder = MessageDecoder::new();
+        let mut messages = Vec::new();
+        loop {
+            match decoder.read_from(reader) {
+                Ok(0) => return messages,
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => continue,
+                Err(e) => panic!("unexpected error: {}", e),
             }
+            while let Some(msg) = decoder.next_message() {
+                messages.push(msg.unwrap());
+            }
+        }
+    }
+
+    fn wire_chunk(payload: &[u8]) -> Message {
+        message(MessageType::WireChunk(WireChunkData {
+            timestamp: TimeVal { sec: 1, usec: 2 },
+            payload: payload.to_vec(),
+        }))
+    }
+
+    #[test]
+    fn decodes_byte_by_byte() {
+        let msgs = vec![wire_chunk(&[1, 2, 3]), wire_chunk(&[])];
+        let mut data = msgs[0].serialize();
+        data.extend(msgs[1].serialize());
+        let mut reader = SlowReader { data: data, pos: 0, blocks: Vec::new() };
+        assert_eq!(decode_all(&mut reader), msgs);
+    }
+
+    #[test]
+    fn decodes_across_would_block_in_header() {
+        let msgs = vec![wire_chunk(&[4, 5, 6]), wire_chunk(&[7])];
+        let first = msgs[0].serialize();
+        let mut data = first.clone();
+        data.extend(msgs[1].serialize());
+        // Block inside both base headers, including the size field.
+        let blocks = vec![0, 5, 23, first.len() + 10];
+        let mut reader = SlowReader { data: data, pos: 0, blocks: blocks };
+        assert_eq!(decode_all(&mut reader), msgs);
+    }
+
+    #[test]
+    fn round_trips_all_types() {
+        let hello = HelloData {
+            mac: "00:11:22:33:44:55".to_string(),
+            hostname: "host".to_string(),
+            version: "0.11.1".to_string(),
+            client_name: "Snaprust".to_string(),
+            os: "linux".to_string(),
+            arch: "x86_64".to_string(),
+            instance: 1,
+            snap_stream_protocol_version: 2,
+        };
+        let types = vec![
+            MessageType::Base(BaseData {}),
+            MessageType::CodecHeader(CodecHeaderData {
+                codec: "flac".to_string(),
+                payload: vec![0x66, 0x4c, 0x61, 0x43],
+            }),
+            MessageType::WireChunk(WireChunkData {
+                timestamp: TimeVal { sec: 1500000000, usec: 999999 },
+                payload: vec![1, 2, 3, 4, 5],
+            }),
+            MessageType::ServerSettings(ServerSettingsData {
+                muted: true,
+                buffer_ms: 1000,
+                latency: -20,
+                volume: 87,
+            }),
+            MessageType::Time(TimeData { latency: TimeVal { sec: -1, usec: 500 } }),
+            MessageType::Hello(hello),
+            MessageType::StreamTags(StreamTagsData {
+                tags: serde_json::from_str(r#"{"artist":"A","title":"T"}"#).unwrap(),
+            }),
+            MessageType::ClientInfo(ClientInfoData { volume: 42, muted: false }),
+            MessageType::Error(ErrorData {
+                code: 1,
+                error: "unsupported".to_string(),
+                message: "codec not supported".to_string(),
+            }),
+            MessageType::Unknown { type_id: 99, raw: vec![9, 8, 7] },
+        ];
+        for type_ in types {
+            let msg = message(type_);
+            assert_eq!(Message::deserialize(&msg.serialize()).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_message() {
+        let mut data = wire_chunk(&[]).serialize();
+        data[22..BASE_MESSAGE_SIZE].copy_from_slice(&serialize_u32(MAX_MESSAGE_SIZE as u32 + 1));
+        let mut decoder = MessageDecoder::new();
+        decoder.feed(&data);
+        match decoder.next_message() {
+            Some(Err(ProtocolError::TooLarge(size))) => assert_eq!(size, MAX_MESSAGE_SIZE + 1),
+            other => panic!("expected TooLarge, got {:?}", other),
         }
     }
 }
@@ -2,40 +2,89 @@ use std::collections::VecDeque;
 use std::sync::Mutex;
 use std::net::TcpStream;
 use std::sync::mpsc;
-use std::thread;
 use std::time;
-use std::io::Write;
+use std::io;
+use std::io::{Write, ErrorKind};
 use std::time::{SystemTime, UNIX_EPOCH, Instant};
 
 use message;
+use time_provider::Clock;
+use std::sync::Arc;
 
 use log;
-use simplelog;
+
+/// Port of the stream server if none is given.
+pub const DEFAULT_PORT: u16 = 1704;
+
+/// Splits `server` into host and port, using `default_port` if it has none.
+///
+/// Accepts "host", "host:port", IPv4 and IPv6 addresses, with IPv6 addresses
+/// in brackets if followed by a port, e.g. "[::1]:1704".
+pub fn split_host_port(server: &str, default_port: u16) -> io::Result<(String, u16)> {
+    let invalid = |e: &str| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", server, e));
+    let (host, port) = if server.starts_with('[') {
+        match server.find(']') {
+            Some(end) => match &server[end + 1..] {
+                "" => (&server[1..end], None),
+                rest if rest.starts_with(':') => (&server[1..end], Some(&rest[1..])),
+                _ => return Err(invalid("unexpected characters after ]")),
+            },
+            None => return Err(invalid("missing ]")),
+        }
+    } else {
+        match (server.find(':'), server.rfind(':')) {
+            // A single colon separates the port, more belong to an IPv6
+            // address.
+            (Some(i), Some(j)) if i == j => (&server[..i], Some(&server[i + 1..])),
+            _ => (server, None),
+        }
+    };
+    if host.is_empty() {
+        return Err(invalid("no host"));
+    }
+    let port = match port {
+        Some(p) => p.parse::<u16>().map_err(|_| invalid("invalid port"))?,
+        None => default_port,
+    };
+    Ok((host.to_string(), port))
+}
+
+/// Longest time (ms) the worker waits for data before it sends queued
+/// messages.
+const READ_TIMEOUT_MS: u64 = 10;
 
 pub struct ClientConnection {
     stream: TcpStream,
     send_message_channel: mpsc::Receiver<message::Message>,
     recv_message_channel: mpsc::Sender<message::Message>,
+    decoder: message::MessageDecoder,
+    clock: Arc<Clock>,
     last_timesync: Instant
 }
 
 impl ClientConnection {
-    pub fn start(host: &str)
-        -> (ClientConnection, mpsc::Sender<message::Message>, mpsc::Receiver<message::Message>) {
-        let mut stream = TcpStream::connect(host).unwrap();
-        stream.set_nonblocking(true);
+    /// Connects to the server at `host`, a name or an IPv4 or IPv6 address,
+    /// and `port`. All addresses the name resolves to are tried in turn.
+    pub fn start(host: &str, port: u16, clock: Arc<Clock>)
+        -> io::Result<(ClientConnection, mpsc::Sender<message::Message>, mpsc::Receiver<message::Message>)> {
+        let stream = TcpStream::connect((host, port))?;
+        info!("Connected to {}", stream.peer_addr()?);
+        // Reads block briefly, so messages are timestamped as soon as they
+        // arrive, while outgoing messages are still sent promptly.
+        stream.set_read_timeout(Some(time::Duration::from_millis(READ_TIMEOUT_MS)))?;
         let (s_msg_tx, s_msg_rx) = mpsc::channel();
         let (r_msg_tx, r_msg_rx) = mpsc::channel();
-        (ClientConnection {
+        Ok((ClientConnection {
             stream: stream,
             send_message_channel: s_msg_rx,
             recv_message_channel: r_msg_tx,
+            decoder: message::MessageDecoder::new(),
+            clock: clock,
             last_timesync: Instant::now(),
-        }, s_msg_tx, r_msg_rx)
+        }, s_msg_tx, r_msg_rx))
     }
 
     pub fn worker(&mut self) {
-        let sleep_period = time::Duration::from_millis(100);
         let time_sync_every = 500;
 
         loop {
@@ -55,34 +104,89 @@ impl ClientConnection {
                     recieved: message::TimeVal::new(),
                     sent: message::TimeVal::new()
                 };
-                let msg = time_msg.serialize();
-                let msg = msg.as_slice();
-                self.stream.write_all(&msg[..]);
+                if let Err(e) = self.send(time_msg) {
+                    error!("Error writing to server: {:?}", e);
+                    return;
+                }
                 info!("Timesync!");
             }
 
             while let Ok(msg) = self.send_message_channel.try_recv() {
-                debug!("Send: {:?}", msg);
-                let msg = msg.serialize();
-                let msg = msg.as_slice();
-                self.stream.write_all(&msg[..]);
+                if let Err(e) = self.send(msg) {
+                    error!("Error writing to server: {:?}", e);
+                    return;
+                }
             }
 
-            while let Ok(msg) = message::Message::deserialize_from_socket(&self.stream) {
-                debug!("Read message: {:?}", msg);
-                self.recv_message_channel.send(msg);
+            match self.decoder.read_from(&mut self.stream) {
+                Ok(0) => {
+                    error!("Connection closed by server");
+                    return;
+                },
+                Ok(_) => {},
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => continue,
+                Err(e) => {
+                    error!("Error reading from server: {:?}", e);
+                    return;
+                }
             }
+            // Messages completed by this read arrived now. Taking the time
+            // later would make the server seem further away.
+            let recieved = message::TimeVal::from_micros(self.clock.now());
 
-            thread::sleep(sleep_period);
+            while let Some(msg) = self.decoder.next_message() {
+                match msg {
+                    Ok(mut msg) => {
+                        msg.recieved = recieved.clone();
+                        debug!("Read message: {:?}", msg);
+                        if self.recv_message_channel.send(msg).is_err() {
+                            return;
+                        }
+                    },
+                    Err(e @ message::ProtocolError::TooLarge(_)) => {
+                        error!("Dropping connection: {}", e);
+                        return;
+                    },
+                    Err(e) => warn!("Dropping malformed message: {}", e),
+                }
+            }
         }
     }
+
+    /// Stamps `msg` with the current time and writes it to the server.
+    fn send(&mut self, mut msg: message::Message) -> io::Result<()> {
+        msg.sent = message::TimeVal::from_micros(self.clock.now());
+        debug!("Send: {:?}", msg);
+        let msg = msg.serialize();
+        self.stream.write_all(&msg)
+    }
 }
 
-pub fn fill_queue(sender: mpsc::Sender<message::Message>, stream: TcpStream) {
-    loop {
-        let msg = message::Message::deserialize_from_socket(&stream);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        sender.send(msg.unwrap()).unwrap();
+    fn split(server: &str) -> Option<(String, u16)> {
+        split_host_port(server, DEFAULT_PORT).ok()
     }
 
+    #[test]
+    fn splits_host_and_port() {
+        assert_eq!(split("host"), Some(("host".to_string(), 1704)));
+        assert_eq!(split("host:1705"), Some(("host".to_string(), 1705)));
+        assert_eq!(split("::1"), Some(("::1".to_string(), 1704)));
+        assert_eq!(split("[::1]:99"), Some(("::1".to_string(), 99)));
+        assert_eq!(split("[::1]"), Some(("::1".to_string(), 1704)));
+    }
+
+    #[test]
+    fn rejects_invalid_servers() {
+        assert_eq!(split("[::1]x"), None);
+        assert_eq!(split("[::1"), None);
+        assert_eq!(split("h:"), None);
+        assert_eq!(split("1.2.3.4:x"), None);
+        assert_eq!(split(""), None);
+        assert_eq!(split(":1705"), None);
+        assert_eq!(split("[]:1705"), None);
+    }
 }
@@ -0,0 +1,185 @@
+use alsa;
+use alsa::{Direction, ValueOr};
+use alsa::device_name::HintIter;
+use alsa::pcm::{PCM, HwParams};
+use output::OutputError;
+use output::alsa_output::alsa_format;
+use samples::SampleType;
+use std::ffi::CString;
+
+/// Highest device number probed on each card. Device numbers need not be
+/// contiguous, e.g. HDMI outputs often start at 3.
+const MAX_DEVICES: u32 = 32;
+/// Error code of ALSA for a device that is in use.
+const EBUSY: i32 = 16;
+/// Rates probed for each hardware device.
+const RATES: [u32; 13] = [8000, 11025, 16000, 22050, 32000, 44100, 48000,
+                          88200, 96000, 176400, 192000, 352800, 384000];
+
+/// A PCM playback device from the ALSA configuration, as accepted by
+/// `AlsaOutput::new`.
+#[derive(Debug, Clone)]
+pub struct PcmDevice {
+    pub name: String,
+    pub description: String,
+}
+
+/// Lists the PCM playback devices of the ALSA configuration, e.g.
+/// "default", "hw:CARD=PCH,DEV=0" or "plughw:CARD=PCH,DEV=0".
+pub fn pcm_devices() -> Result<Vec<PcmDevice>, OutputError> {
+    let hints = HintIter::new(None, &*CString::new("pcm").unwrap())?;
+    Ok(hints
+        .filter(|h| h.direction.map_or(true, |d| d == Direction::Playback))
+        .filter_map(|h| {
+            let description = h.desc.unwrap_or_default();
+            h.name.map(|name| PcmDevice {
+                name: name,
+                description: description,
+            })
+        })
+        .collect())
+}
+
+/// Resolves the device selected with `--soundcard`: an index into
+/// `pcm_devices` or a PCM name such as "default", "hw:1,0" or "plughw:1".
+pub fn select_device(selector: &str) -> Result<String, OutputError> {
+    match selector.parse::<usize>() {
+        Ok(index) => pcm_devices()?
+            .into_iter()
+            .nth(index)
+            .map(|d| d.name)
+            .ok_or(OutputError::Open(format!("no PCM device with index {}", index))),
+        Err(_) => Ok(selector.to_string())
+    }
+}
+
+/// Returns the control device of the card a PCM `device` plays on, e.g.
+/// "hw:1" for "plughw:1,0" and "hw:PCH" for "front:CARD=PCH,DEV=0".
+/// Devices without a card, such as "default", map to "default".
+pub fn mixer_card(device: &str) -> String {
+    let args = match device.find(':') {
+        Some(i) => &device[i + 1..],
+        None => return "default".to_string(),
+    };
+    let card = match args.split(',').find(|a| a.starts_with("CARD=")) {
+        Some(a) => &a["CARD=".len()..],
+        None => args.split(',').next().unwrap_or(""),
+    };
+    if card.is_empty() || card.contains('=') {
+        return "default".to_string();
+    }
+    format!("hw:{}", card)
+}
+
+/// A subdevice of a sound card and the configurations it supports.
+#[derive(Debug, Clone)]
+pub struct HwDevice {
+    pub card: i32,
+    pub device: u32,
+    pub subdevice: u32,
+    pub name: String,
+    pub subdevice_name: String,
+    /// Whether the subdevice is in use, in which case its capabilities are
+    /// unknown.
+    pub busy: bool,
+    pub rates: Vec<u32>,
+    pub sample_types: Vec<SampleType>,
+}
+
+/// A sound card with its playback subdevices.
+#[derive(Debug, Clone)]
+pub struct SoundCard {
+    pub index: i32,
+    pub name: String,
+    pub longname: String,
+    pub devices: Vec<HwDevice>,
+}
+
+/// Lists the sound cards and probes their playback subdevices.
+pub fn sound_cards() -> Result<Vec<SoundCard>, OutputError> {
+    let mut cards = Vec::new();
+    for card in alsa::card::Iter::new() {
+        let card = card?;
+        let index = card.get_index();
+        let mut devices = Vec::new();
+        for device in 0..MAX_DEVICES {
+            // Subdevices are numbered from 0, stop at the first one that
+            // does not exist.
+            let mut subdevice = 0;
+            while let Some(d) = probe(index, device, subdevice) {
+                devices.push(d);
+                subdevice += 1;
+            }
+        }
+        cards.push(SoundCard {
+            index: index,
+            name: card.get_name()?,
+            longname: card.get_longname()?,
+            devices: devices,
+        });
+    }
+    Ok(cards)
+}
+
+/// Opens a playback subdevice to find out what it supports. Returns `None`
+/// if it does not exist.
+fn probe(card: i32, device: u32, subdevice: u32) -> Option<HwDevice> {
+    let name = CString::new(format!("hw:{},{},{}", card, device, subdevice)).unwrap();
+    let pcm = match PCM::open(&*name, Direction::Playback, true) {
+        Ok(pcm) => pcm,
+        Err(ref e) if e.code() == -EBUSY => return Some(HwDevice {
+            card: card,
+            device: device,
+            subdevice: subdevice,
+            name: String::new(),
+            subdevice_name: String::new(),
+            busy: true,
+            rates: Vec::new(),
+            sample_types: Vec::new(),
+        }),
+        Err(_) => return None,
+    };
+    let (device_name, subdevice_name) = match pcm.info() {
+        Ok(info) => (info.get_name().unwrap_or("").to_string(),
+                     info.get_subdevice_name().unwrap_or("").to_string()),
+        Err(_) => (String::new(), String::new()),
+    };
+    // Each value is tested against the full configuration space.
+    let supports = |f: &Fn(&HwParams) -> alsa::Result<()>| {
+        HwParams::any(&pcm).and_then(|hwp| f(&hwp)).is_ok()
+    };
+    let rates = RATES.iter()
+        .cloned()
+        .filter(|&r| supports(&|hwp| hwp.set_rate(r, ValueOr::Nearest)))
+        .collect();
+    let sample_types = [SampleType::S16, SampleType::S24, SampleType::S32, SampleType::F32].iter()
+        .cloned()
+        .filter(|&t| supports(&|hwp| hwp.set_format(alsa_format(t))))
+        .collect();
+    Some(HwDevice {
+        card: card,
+        device: device,
+        subdevice: subdevice,
+        name: device_name,
+        subdevice_name: subdevice_name,
+        busy: false,
+        rates: rates,
+        sample_types: sample_types,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_mixer_card_of_device() {
+        assert_eq!(mixer_card("default"), "default");
+        assert_eq!(mixer_card("hw:1"), "hw:1");
+        assert_eq!(mixer_card("plughw:1,0"), "hw:1");
+        assert_eq!(mixer_card("hw:CARD=PCH,DEV=0"), "hw:PCH");
+        assert_eq!(mixer_card("front:CARD=PCH,DEV=0"), "hw:PCH");
+        assert_eq!(mixer_card("sysdefault:CARD=Device"), "hw:Device");
+        assert_eq!(mixer_card("dmix:DEV=0"), "default");
+    }
+}
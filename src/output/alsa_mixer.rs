@@ -0,0 +1,77 @@
+use alsa::Round;
+use alsa::mixer::{Mixer, MilliBel, Selem, SelemId};
+use output::OutputError;
+use volume::VolumeControl;
+
+/// Range (dB) the volume is mapped to when the element supports more.
+const DB_RANGE: f32 = 60.0;
+
+/// Volume control on an ALSA simple mixer element, e.g. "Master" or "PCM".
+///
+/// If the element reports its dB range, the volume is mapped to it like
+/// `SoftwareVolume` maps it to a gain, i.e. 60dB over the full range.
+/// Otherwise the volume is mapped linearly to the raw range of the element.
+/// Mute uses the switch of the element if it has one, otherwise the
+/// minimum volume.
+pub struct AlsaMixer {
+    mixer: Mixer,
+    card: String,
+    element: String,
+}
+
+impl AlsaMixer {
+    /// Opens element `element` of the mixer of `card`, e.g. "default" or
+    /// "hw:1". Fails if the element has no playback volume.
+    pub fn new(card: &str, element: &str) -> Result<AlsaMixer, OutputError> {
+        let mixer = Mixer::new(card, false)
+            .map_err(|e| OutputError::Open(format!("mixer {}: {}", card, e)))?;
+        let mixer = AlsaMixer {
+            mixer: mixer,
+            card: card.to_string(),
+            element: element.to_string(),
+        };
+        match mixer.selem() {
+            Some(ref s) if s.has_playback_volume() => {},
+            Some(_) => return Err(OutputError::Open(format!("{} on {} has no playback volume", element, card))),
+            None => return Err(OutputError::Open(format!("no mixer element {} on {}", element, card))),
+        }
+        Ok(mixer)
+    }
+
+    fn selem(&self) -> Option<Selem<'_>> {
+        self.mixer.find_selem(&SelemId::new(&self.element, 0))
+    }
+
+    fn set_volume(&self, volume: u16, muted: bool) -> Result<(), OutputError> {
+        let selem = match self.selem() {
+            Some(s) => s,
+            None => return Err(OutputError::Device(format!("mixer element {} is gone", self.element))),
+        };
+        let v = volume.min(100) as f32 / 100.0;
+        let (min_db, max_db) = selem.get_playback_db_range();
+        let switch = selem.has_playback_switch();
+        if (muted && !switch) || v == 0.0 {
+            let (min, _) = selem.get_playback_volume_range();
+            selem.set_playback_volume_all(min)?;
+        } else if max_db.0 > min_db.0 {
+            let db = (max_db.to_db() + 20.0 * (v * v * v).log10()).max(max_db.to_db() - DB_RANGE);
+            let db = MilliBel::from_db(db).0.max(min_db.0);
+            selem.set_playback_db_all(MilliBel(db), Round::Floor)?;
+        } else {
+            let (min, max) = selem.get_playback_volume_range();
+            selem.set_playback_volume_all(min + ((max - min) as f32 * v).round() as i64)?;
+        }
+        if switch {
+            selem.set_playback_switch_all(if muted { 0 } else { 1 })?;
+        }
+        Ok(())
+    }
+}
+
+impl VolumeControl for AlsaMixer {
+    fn set(&mut self, volume: u16, muted: bool) {
+        if let Err(e) = self.set_volume(volume, muted) {
+            error!("Cannot set volume of {} on {}: {}", self.element, self.card, e);
+        }
+    }
+}
@@ -0,0 +1,175 @@
+use alsa;
+use alsa::{Direction, ValueOr};
+use alsa::pcm::{PCM, HwParams, Format, Access};
+use decoder::SampleFormat;
+use output::{AudioOutput, OutputError, OutputFormat};
+use samples::{SampleBuffer, SampleType};
+use std::ffi::CString;
+
+/// Default period time (ms); short periods keep the delay accurate.
+pub const DEFAULT_PERIOD_MS: u32 = 10;
+/// Default buffer time (ms), enough to ride out scheduling hiccups.
+pub const DEFAULT_BUFFER_MS: u32 = 60;
+/// Error code of ALSA for an underrun.
+const EPIPE: i32 = 32;
+
+impl From<alsa::Error> for OutputError {
+    fn from(e: alsa::Error) -> OutputError {
+        OutputError::Device(e.to_string())
+    }
+}
+
+/// ALSA format of the bytes `SampleBuffer::to_bytes` produces.
+pub fn alsa_format(sample_type: SampleType) -> Format {
+    match sample_type {
+        SampleType::S16 => Format::S16LE,
+        SampleType::S24 => Format::S24LE,
+        SampleType::S32 => Format::S32LE,
+        SampleType::F32 => Format::FloatLE,
+    }
+}
+
+/// Output to an ALSA PCM device.
+///
+/// The device transfers the audio in periods of `period_ms` from a buffer of
+/// `buffer_ms`. Playback starts as soon as the first period is written, so
+/// the queued frames reported by `delay` are always being played.
+pub struct AlsaOutput {
+    device: String,
+    period_ms: u32,
+    buffer_ms: u32,
+    pcm: Option<PCM>,
+    channels: usize,
+    underruns: u64,
+}
+
+impl AlsaOutput {
+    /// Creates an output for the PCM device `device`, e.g. "default". The
+    /// device is opened by `open`.
+    pub fn new(device: &str, period_ms: u32, buffer_ms: u32) -> AlsaOutput {
+        AlsaOutput {
+            device: device.to_string(),
+            period_ms: period_ms,
+            buffer_ms: buffer_ms,
+            pcm: None,
+            channels: 0,
+            underruns: 0,
+        }
+    }
+
+    fn pcm(&self) -> Result<&PCM, OutputError> {
+        self.pcm.as_ref().ok_or(OutputError::NotOpen)
+    }
+
+    /// Configures the device for `format` and returns the sample type it
+    /// accepts.
+    ///
+    /// The sample type matching the bit depth of the stream is preferred. If
+    /// the device does not support it, the next best one is used and the
+    /// audio is converted.
+    fn set_hw_params(&self, hwp: &HwParams, format: &SampleFormat) -> Result<SampleType, OutputError> {
+        hwp.set_channels(format.channels as u32)?;
+        hwp.set_rate(format.rate, ValueOr::Nearest)?;
+        hwp.set_access(Access::RWInterleaved)?;
+        // The audio is not resampled, at another rate it would play too
+        // fast or too slow.
+        let rate = hwp.get_rate()?;
+        if rate != format.rate {
+            return Err(OutputError::Open(format!("device does not support {}Hz, nearest rate is {}Hz",
+                format.rate, rate)));
+        }
+        let rate = rate as i64;
+        hwp.set_period_size_near(rate * self.period_ms as i64 / 1000, ValueOr::Nearest)?;
+        hwp.set_buffer_size_near(rate * self.buffer_ms as i64 / 1000)?;
+        let candidates = match SampleType::for_bits(format.bits) {
+            SampleType::S16 => vec![SampleType::S16, SampleType::S32, SampleType::S24, SampleType::F32],
+            SampleType::S24 => vec![SampleType::S24, SampleType::S32, SampleType::F32, SampleType::S16],
+            _ => vec![SampleType::S32, SampleType::F32, SampleType::S24, SampleType::S16],
+        };
+        for sample_type in candidates {
+            if hwp.set_format(alsa_format(sample_type)).is_ok() {
+                return Ok(sample_type);
+            }
+            debug!("Device does not support {:?}", sample_type);
+        }
+        Err(OutputError::Open("device supports none of the sample formats".to_string()))
+    }
+}
+
+impl AudioOutput for AlsaOutput {
+    fn open(&mut self, format: &SampleFormat) -> Result<OutputFormat, OutputError> {
+        self.close();
+        let name = CString::new(self.device.as_str())
+            .map_err(|_| OutputError::Open(format!("invalid device name {:?}", self.device)))?;
+        let pcm = PCM::open(&*name, Direction::Playback, false)
+            .map_err(|e| OutputError::Open(format!("{}: {}", self.device, e)))?;
+        let output_format = {
+            let hwp = HwParams::any(&pcm)?;
+            let sample_type = self.set_hw_params(&hwp, format)?;
+            pcm.hw_params(&hwp).map_err(|e| OutputError::Open(e.to_string()))?;
+            let period = hwp.get_period_size()?;
+            let swp = pcm.sw_params_current()?;
+            swp.set_start_threshold(period)?;
+            pcm.sw_params(&swp).map_err(|e| OutputError::Open(e.to_string()))?;
+            let output_format = OutputFormat {
+                rate: hwp.get_rate()?,
+                channels: hwp.get_channels()? as u16,
+                sample_type: sample_type,
+            };
+            info!("Opened {}: {} Hz, {} channels, {:?}, period {} frames, buffer {} frames", self.device,
+                output_format.rate, output_format.channels, output_format.sample_type,
+                period, hwp.get_buffer_size()?);
+            output_format
+        };
+        self.channels = output_format.channels as usize;
+        self.underruns = 0;
+        self.pcm = Some(pcm);
+        Ok(output_format)
+    }
+
+    fn write(&mut self, samples: &SampleBuffer) -> Result<(), OutputError> {
+        let pcm = self.pcm.as_ref().ok_or(OutputError::NotOpen)?;
+        let bytes = samples.to_bytes();
+        let frame_size = samples.sample_type().bytes() * self.channels;
+        let io = pcm.io();
+        let mut written = 0;
+        while written < bytes.len() {
+            match io.writei(&bytes[written..]) {
+                Ok(frames) => written += frames * frame_size,
+                Err(e) => {
+                    if e.code() == -EPIPE {
+                        self.underruns += 1;
+                    } else {
+                        warn!("Write to {} failed ({}), recovering", self.device, e);
+                    }
+                    // Gives up on errors that cannot be recovered from.
+                    pcm.recover(e.code(), true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn delay(&mut self) -> Result<usize, OutputError> {
+        let (_, delay) = self.pcm()?.avail_delay()?;
+        Ok(delay.max(0) as usize)
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), OutputError> {
+        Ok(self.pcm()?.pause(pause)?)
+    }
+
+    fn drain(&mut self) -> Result<(), OutputError> {
+        Ok(self.pcm()?.drain()?)
+    }
+
+    fn close(&mut self) {
+        if let Some(pcm) = self.pcm.take() {
+            let _ = pcm.drop();
+        }
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns
+    }
+}
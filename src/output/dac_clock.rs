@@ -0,0 +1,106 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Simulated sound card clock for outputs that take audio faster than real
+/// time, such as files and pipes.
+///
+/// Written frames are "played" at `rate` frames per second from a buffer of
+/// `buffer` frames. Writes block while the buffer is full, so the audio is
+/// produced at the pace and with the delay a sound card would have. Like a
+/// sound card, the clock stops when the buffer runs empty and starts again
+/// with the next write.
+#[derive(Debug)]
+pub struct DacClock {
+    rate: u32,
+    buffer: u64,
+    /// Frames written since the output was opened.
+    written: u64,
+    /// Frames played when the clock was last started or stopped.
+    played: u64,
+    /// When the clock was last started, `None` while it is stopped.
+    started: Option<Instant>,
+    paused: bool,
+    underruns: u64,
+}
+
+impl DacClock {
+    pub fn new(rate: u32, buffer: usize) -> DacClock {
+        DacClock {
+            rate: rate,
+            buffer: buffer as u64,
+            written: 0,
+            played: 0,
+            started: None,
+            paused: false,
+            underruns: 0,
+        }
+    }
+
+    /// Frames written since the output was opened.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    /// Frames played since the output was opened.
+    pub fn played(&self) -> u64 {
+        match self.started {
+            Some(started) => {
+                let elapsed = started.elapsed();
+                let frames = elapsed.as_secs() * self.rate as u64
+                    + elapsed.subsec_nanos() as u64 * self.rate as u64 / 1_000_000_000;
+                (self.played + frames).min(self.written)
+            },
+            None => self.played
+        }
+    }
+
+    /// Frames written but not yet played.
+    pub fn delay(&self) -> usize {
+        (self.written - self.played()) as usize
+    }
+
+    /// Waits until there is room for `frames` frames and accounts for them
+    /// as written.
+    pub fn write(&mut self, frames: usize) {
+        if !self.paused && self.delay() == 0 {
+            // Start, or restart after an underrun.
+            if self.written > 0 {
+                self.underruns += 1;
+            }
+            self.played = self.written;
+            self.started = Some(Instant::now());
+        }
+        let excess = (self.delay() as u64 + frames as u64).saturating_sub(self.buffer);
+        if excess > 0 && !self.paused {
+            thread::sleep(self.frames_to_duration(excess));
+        }
+        self.written += frames as u64;
+    }
+
+    /// Number of times the buffer ran empty.
+    pub fn underruns(&self) -> u64 {
+        self.underruns
+    }
+
+    pub fn pause(&mut self, pause: bool) {
+        if pause == self.paused {
+            return;
+        }
+        self.played = self.played();
+        self.started = if pause { None } else { Some(Instant::now()) };
+        self.paused = pause;
+    }
+
+    /// Waits until all written frames have been played.
+    pub fn drain(&mut self) {
+        if !self.paused {
+            let delay = self.delay() as u64;
+            thread::sleep(self.frames_to_duration(delay));
+        }
+    }
+
+    fn frames_to_duration(&self, frames: u64) -> Duration {
+        let us = frames * 1_000_000 / self.rate as u64;
+        Duration::new(us / 1_000_000, (us % 1_000_000) as u32 * 1000)
+    }
+}
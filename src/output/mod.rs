@@ -0,0 +1,94 @@
+use decoder::SampleFormat;
+use samples::{SampleBuffer, SampleType};
+use std::error;
+use std::fmt;
+
+mod dac_clock;
+mod alsa_output;
+pub use self::alsa_output::{AlsaOutput, DEFAULT_PERIOD_MS, DEFAULT_BUFFER_MS};
+mod alsa_devices;
+pub use self::alsa_devices::{pcm_devices, select_device, sound_cards, mixer_card};
+mod alsa_mixer;
+pub use self::alsa_mixer::AlsaMixer;
+mod wav_output;
+pub use self::wav_output::WavOutput;
+mod pipe_output;
+pub use self::pipe_output::PipeOutput;
+mod null_output;
+pub use self::null_output::NullOutput;
+
+/// Buffer (ms) of the outputs that simulate a sound card.
+const OUTPUT_BUFFER_MS: usize = 100;
+
+/// Format the output was opened with, which may differ from the format of
+/// the stream if the device does not support it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputFormat {
+    pub rate: u32,
+    pub channels: u16,
+    pub sample_type: SampleType,
+}
+
+/// Errors reported by outputs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum OutputError {
+    /// The output could not be opened or configured.
+    Open(String),
+    /// Audio could not be written or the output could not be controlled.
+    Device(String),
+    /// The output was used before `open`.
+    NotOpen,
+}
+
+impl fmt::Display for OutputError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &OutputError::Open(ref e) => write!(f, "cannot open output: {}", e),
+            &OutputError::Device(ref e) => write!(f, "output error: {}", e),
+            &OutputError::NotOpen => write!(f, "output not open"),
+        }
+    }
+}
+
+impl error::Error for OutputError {
+    fn description(&self) -> &str {
+        match self {
+            &OutputError::Open(_) => "cannot open output",
+            &OutputError::Device(_) => "output error",
+            &OutputError::NotOpen => "output not open",
+        }
+    }
+}
+
+/// Plays interleaved audio.
+///
+/// The scheduler decides which frames are written when; an output only has
+/// to play them in order and tell how long it takes until a written frame is
+/// heard.
+pub trait AudioOutput {
+    /// Opens the output for audio in `format`, closing it first if it is
+    /// already open. Returns the format the samples passed to `write` must
+    /// have.
+    fn open(&mut self, format: &SampleFormat) -> Result<OutputFormat, OutputError>;
+    /// Plays `samples`, blocking until they are queued.
+    fn write(&mut self, samples: &SampleBuffer) -> Result<(), OutputError>;
+    /// Number of frames written but not yet heard.
+    fn delay(&mut self) -> Result<usize, OutputError>;
+    /// Pauses or resumes playback, keeping the queued frames.
+    fn pause(&mut self, pause: bool) -> Result<(), OutputError>;
+    /// Blocks until all queued frames have been played.
+    fn drain(&mut self) -> Result<(), OutputError>;
+    /// Closes the output, discarding queued frames.
+    fn close(&mut self);
+    /// Number of times the output ran out of audio since it was opened.
+    /// The audio written after an underrun is delayed by the gap.
+    fn underruns(&self) -> u64 {
+        0
+    }
+    /// Tells the output the offset (us) of the server clock to the local
+    /// clock, for outputs that report server times.
+    fn set_diff_to_server(&mut self, _diff: i64) {}
+    /// Tells the output that frame `frame` of the next write was captured at
+    /// server time `timestamp` (us), for outputs that report it.
+    fn set_source_time(&mut self, _frame: usize, _timestamp: i64) {}
+}
@@ -0,0 +1,106 @@
+use decoder::SampleFormat;
+use output::{AudioOutput, OutputError, OutputFormat, OUTPUT_BUFFER_MS};
+use output::dac_clock::DacClock;
+use samples::{SampleBuffer, SampleType};
+use std::sync::Arc;
+use time_provider::Clock;
+
+/// Interval (ms of audio) between two reports of the null output.
+const REPORT_INTERVAL_MS: u64 = 1000;
+
+/// Discards the audio at the pace of a sound card.
+///
+/// Meant for verifying synchronization without audio hardware: about every
+/// `REPORT_INTERVAL_MS` it logs the server time a frame was captured at and
+/// the server time the simulated DAC plays it at. Clients playing the same
+/// stream in sync report the same play time for the same capture time, i.e.
+/// the same distance between the two.
+pub struct NullOutput {
+    clock: Arc<Clock>,
+    dac: Option<DacClock>,
+    rate: u32,
+    channels: usize,
+    /// Offset (us) of the server clock to `clock`.
+    diff_to_server: i64,
+    /// Frame of the next write and server time (us) it was captured at.
+    source: Option<(usize, i64)>,
+    /// Number of written frames after which the next report is due.
+    next_report: u64,
+}
+
+impl NullOutput {
+    pub fn new(clock: Arc<Clock>) -> NullOutput {
+        NullOutput {
+            clock: clock,
+            dac: None,
+            rate: 0,
+            channels: 0,
+            diff_to_server: 0,
+            source: None,
+            next_report: 0,
+        }
+    }
+}
+
+impl AudioOutput for NullOutput {
+    fn open(&mut self, format: &SampleFormat) -> Result<OutputFormat, OutputError> {
+        self.close();
+        self.dac = Some(DacClock::new(format.rate, format.rate as usize * OUTPUT_BUFFER_MS / 1000));
+        self.rate = format.rate;
+        self.channels = format.channels as usize;
+        self.next_report = 0;
+        Ok(OutputFormat {
+            rate: format.rate,
+            channels: format.channels,
+            sample_type: SampleType::for_bits(format.bits),
+        })
+    }
+
+    fn write(&mut self, samples: &SampleBuffer) -> Result<(), OutputError> {
+        let dac = self.dac.as_mut().ok_or(OutputError::NotOpen)?;
+        let frames = samples.len() / self.channels;
+        dac.write(frames);
+        let source = self.source.take();
+        if dac.written() < self.next_report {
+            return Ok(());
+        }
+        if let Some((frame, captured)) = source {
+            // Frames queued before this write are played first.
+            let position = dac.written() - frames as u64 + frame as u64;
+            let queued = position.saturating_sub(dac.played());
+            let local = self.clock.now() + (queued * 1_000_000 / self.rate as u64) as i64;
+            let played = local + self.diff_to_server;
+            info!("Audio captured at server time {}us plays at {}us ({}us later)", captured, played, played - captured);
+            self.next_report = dac.written() + REPORT_INTERVAL_MS * self.rate as u64 / 1000;
+        }
+        Ok(())
+    }
+
+    fn delay(&mut self) -> Result<usize, OutputError> {
+        self.dac.as_ref().map(|d| d.delay()).ok_or(OutputError::NotOpen)
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), OutputError> {
+        self.dac.as_mut().map(|d| d.pause(pause)).ok_or(OutputError::NotOpen)
+    }
+
+    fn drain(&mut self) -> Result<(), OutputError> {
+        self.dac.as_mut().map(|d| d.drain()).ok_or(OutputError::NotOpen)
+    }
+
+    fn close(&mut self) {
+        self.dac = None;
+    }
+
+    fn underruns(&self) -> u64 {
+        self.dac.as_ref().map_or(0, |d| d.underruns())
+    }
+
+    fn set_diff_to_server(&mut self, diff: i64) {
+        self.diff_to_server = diff;
+    }
+
+    fn set_source_time(&mut self, frame: usize, timestamp: i64) {
+        self.source = Some((frame, timestamp));
+    }
+}
@@ -0,0 +1,119 @@
+use decoder::SampleFormat;
+use output::{AudioOutput, OutputError, OutputFormat, OUTPUT_BUFFER_MS};
+use output::dac_clock::DacClock;
+use samples::{SampleBuffer, SampleType};
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+
+impl From<io::Error> for OutputError {
+    fn from(e: io::Error) -> OutputError {
+        OutputError::Device(e.to_string())
+    }
+}
+
+/// Writes raw interleaved little endian samples in the bit depth of the
+/// stream to stdout or a file, e.g. a named pipe.
+///
+/// As with a sound card, the audio is written in real time, and a reader
+/// that does not keep up delays playback until the scheduler resyncs.
+pub struct PipeOutput {
+    /// Path to write to, stdout if `None`.
+    path: Option<PathBuf>,
+    writer: Option<Box<Write>>,
+    clock: Option<DacClock>,
+    channels: usize,
+}
+
+impl PipeOutput {
+    pub fn stdout() -> PipeOutput {
+        PipeOutput::with_path(None)
+    }
+
+    /// Writes to `path`, which is created if it does not exist.
+    pub fn new<P: Into<PathBuf>>(path: P) -> PipeOutput {
+        PipeOutput::with_path(Some(path.into()))
+    }
+
+    fn with_path(path: Option<PathBuf>) -> PipeOutput {
+        PipeOutput {
+            path: path,
+            writer: None,
+            clock: None,
+            channels: 0,
+        }
+    }
+
+    fn name(&self) -> String {
+        match self.path {
+            Some(ref p) => p.display().to_string(),
+            None => "stdout".to_string(),
+        }
+    }
+}
+
+impl AudioOutput for PipeOutput {
+    fn open(&mut self, format: &SampleFormat) -> Result<OutputFormat, OutputError> {
+        self.close();
+        let writer: Box<Write> = match self.path {
+            // Opening a FIFO blocks until there is a reader.
+            Some(ref p) => Box::new(OpenOptions::new().write(true).create(true).truncate(true).open(p)
+                .map_err(|e| OutputError::Open(format!("{}: {}", p.display(), e)))?),
+            None => Box::new(io::stdout()),
+        };
+        let sample_type = SampleType::for_bits(format.bits);
+        info!("Writing raw audio to {}: {} Hz, {} channels, {:?}", self.name(),
+            format.rate, format.channels, sample_type);
+        self.writer = Some(writer);
+        self.clock = Some(DacClock::new(format.rate, format.rate as usize * OUTPUT_BUFFER_MS / 1000));
+        self.channels = format.channels as usize;
+        Ok(OutputFormat {
+            rate: format.rate,
+            channels: format.channels,
+            sample_type: sample_type,
+        })
+    }
+
+    fn write(&mut self, samples: &SampleBuffer) -> Result<(), OutputError> {
+        match (self.writer.as_mut(), self.clock.as_mut()) {
+            (Some(writer), Some(clock)) => {
+                clock.write(samples.len() / self.channels);
+                Ok(writer.write_all(&samples.to_bytes())?)
+            },
+            _ => Err(OutputError::NotOpen)
+        }
+    }
+
+    fn delay(&mut self) -> Result<usize, OutputError> {
+        self.clock.as_ref().map(|c| c.delay()).ok_or(OutputError::NotOpen)
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), OutputError> {
+        self.clock.as_mut().map(|c| c.pause(pause)).ok_or(OutputError::NotOpen)
+    }
+
+    fn drain(&mut self) -> Result<(), OutputError> {
+        match (self.writer.as_mut(), self.clock.as_mut()) {
+            (Some(writer), Some(clock)) => {
+                writer.flush()?;
+                clock.drain();
+                Ok(())
+            },
+            _ => Err(OutputError::NotOpen)
+        }
+    }
+
+    fn close(&mut self) {
+        self.clock = None;
+        if let Some(mut writer) = self.writer.take() {
+            if let Err(e) = writer.flush() {
+                error!("Cannot flush {}: {}", self.name(), e);
+            }
+        }
+    }
+
+    fn underruns(&self) -> u64 {
+        self.clock.as_ref().map_or(0, |c| c.underruns())
+    }
+}
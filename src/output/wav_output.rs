@@ -0,0 +1,109 @@
+use decoder::SampleFormat;
+use hound;
+use output::{AudioOutput, OutputError, OutputFormat, OUTPUT_BUFFER_MS};
+use output::dac_clock::DacClock;
+use samples::{SampleBuffer, SampleType};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+
+impl From<hound::Error> for OutputError {
+    fn from(e: hound::Error) -> OutputError {
+        OutputError::Device(e.to_string())
+    }
+}
+
+/// Records the audio to a WAV file in the bit depth of the stream.
+///
+/// The file is written at the pace of a sound card, so it contains exactly
+/// what the client would have played. Opening the output for a new stream
+/// starts the file over.
+pub struct WavOutput {
+    path: PathBuf,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    clock: Option<DacClock>,
+    channels: usize,
+}
+
+impl WavOutput {
+    pub fn new<P: Into<PathBuf>>(path: P) -> WavOutput {
+        WavOutput {
+            path: path.into(),
+            writer: None,
+            clock: None,
+            channels: 0,
+        }
+    }
+}
+
+impl AudioOutput for WavOutput {
+    fn open(&mut self, format: &SampleFormat) -> Result<OutputFormat, OutputError> {
+        self.close();
+        let sample_type = SampleType::for_bits(format.bits);
+        let spec = hound::WavSpec {
+            channels: format.channels,
+            sample_rate: format.rate,
+            bits_per_sample: match sample_type {
+                SampleType::S16 => 16,
+                SampleType::S24 => 24,
+                _ => 32,
+            },
+            sample_format: match sample_type {
+                SampleType::F32 => hound::SampleFormat::Float,
+                _ => hound::SampleFormat::Int,
+            },
+        };
+        let writer = hound::WavWriter::create(&self.path, spec)
+            .map_err(|e| OutputError::Open(format!("{}: {}", self.path.display(), e)))?;
+        info!("Recording to {}: {} Hz, {} channels, {:?}", self.path.display(),
+            format.rate, format.channels, sample_type);
+        self.writer = Some(writer);
+        self.clock = Some(DacClock::new(format.rate, format.rate as usize * OUTPUT_BUFFER_MS / 1000));
+        self.channels = format.channels as usize;
+        Ok(OutputFormat {
+            rate: format.rate,
+            channels: format.channels,
+            sample_type: sample_type,
+        })
+    }
+
+    fn write(&mut self, samples: &SampleBuffer) -> Result<(), OutputError> {
+        match (self.writer.as_mut(), self.clock.as_mut()) {
+            (Some(writer), Some(clock)) => {
+                clock.write(samples.len() / self.channels);
+                match *samples {
+                    SampleBuffer::S16(ref v) => for s in v { writer.write_sample(*s)?; },
+                    SampleBuffer::S24(ref v) | SampleBuffer::S32(ref v) => for s in v { writer.write_sample(*s)?; },
+                    SampleBuffer::F32(ref v) => for s in v { writer.write_sample(*s)?; },
+                }
+                Ok(())
+            },
+            _ => Err(OutputError::NotOpen)
+        }
+    }
+
+    fn delay(&mut self) -> Result<usize, OutputError> {
+        self.clock.as_ref().map(|c| c.delay()).ok_or(OutputError::NotOpen)
+    }
+
+    fn pause(&mut self, pause: bool) -> Result<(), OutputError> {
+        self.clock.as_mut().map(|c| c.pause(pause)).ok_or(OutputError::NotOpen)
+    }
+
+    fn drain(&mut self) -> Result<(), OutputError> {
+        self.clock.as_mut().map(|c| c.drain()).ok_or(OutputError::NotOpen)
+    }
+
+    fn close(&mut self) {
+        self.clock = None;
+        if let Some(writer) = self.writer.take() {
+            if let Err(e) = writer.finalize() {
+                error!("Cannot finish {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    fn underruns(&self) -> u64 {
+        self.clock.as_ref().map_or(0, |c| c.underruns())
+    }
+}
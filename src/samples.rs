@@ -0,0 +1,180 @@
+use byteorder::{ByteOrder, LittleEndian};
+use std::ops::Range;
+
+/// Encoding of the samples in a `SampleBuffer`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleType {
+    S16,
+    /// 24 bit signed integer in the low bits of an `i32`.
+    S24,
+    S32,
+    /// Float in the range -1.0 to 1.0.
+    F32,
+}
+
+impl SampleType {
+    /// Sample type that carries `bits` bits without loss.
+    pub fn for_bits(bits: u16) -> SampleType {
+        match bits {
+            0..=16 => SampleType::S16,
+            17..=24 => SampleType::S24,
+            _ => SampleType::S32,
+        }
+    }
+
+    /// Size of one sample in the output byte stream.
+    pub fn bytes(&self) -> usize {
+        match *self {
+            SampleType::S16 => 2,
+            _ => 4,
+        }
+    }
+}
+
+/// Sample types that can be stored in a `SampleBuffer`.
+pub trait Sample: Copy {
+    fn silence() -> Self;
+    fn scale(self, gain: f32) -> Self;
+    fn is_negative(self) -> bool;
+}
+
+impl Sample for i16 {
+    fn silence() -> i16 { 0 }
+    fn scale(self, gain: f32) -> i16 { (self as f32 * gain) as i16 }
+    fn is_negative(self) -> bool { self < 0 }
+}
+
+impl Sample for i32 {
+    fn silence() -> i32 { 0 }
+    fn scale(self, gain: f32) -> i32 { (self as f64 * gain as f64) as i32 }
+    fn is_negative(self) -> bool { self < 0 }
+}
+
+impl Sample for f32 {
+    fn silence() -> f32 { 0.0 }
+    fn scale(self, gain: f32) -> f32 { self * gain }
+    fn is_negative(self) -> bool { self < 0.0 }
+}
+
+/// Interleaved audio in one of the supported sample types.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SampleBuffer {
+    S16(Vec<i16>),
+    S24(Vec<i32>),
+    S32(Vec<i32>),
+    F32(Vec<f32>),
+}
+
+/// Applies `$body` to the vector inside a `SampleBuffer`, whatever its type.
+macro_rules! with_samples {
+    ($buf:expr, $v:pat => $body:expr) => {
+        match $buf {
+            SampleBuffer::S16($v) => $body,
+            SampleBuffer::S24($v) => $body,
+            SampleBuffer::S32($v) => $body,
+            SampleBuffer::F32($v) => $body,
+        }
+    }
+}
+
+impl SampleBuffer {
+    /// Creates a buffer of `len` silent samples.
+    pub fn silence(sample_type: SampleType, len: usize) -> SampleBuffer {
+        match sample_type {
+            SampleType::S16 => SampleBuffer::S16(vec![0; len]),
+            SampleType::S24 => SampleBuffer::S24(vec![0; len]),
+            SampleType::S32 => SampleBuffer::S32(vec![0; len]),
+            SampleType::F32 => SampleBuffer::F32(vec![0.0; len]),
+        }
+    }
+
+    pub fn sample_type(&self) -> SampleType {
+        match *self {
+            SampleBuffer::S16(_) => SampleType::S16,
+            SampleBuffer::S24(_) => SampleType::S24,
+            SampleBuffer::S32(_) => SampleType::S32,
+            SampleBuffer::F32(_) => SampleType::F32,
+        }
+    }
+
+    /// Number of samples (not frames).
+    pub fn len(&self) -> usize {
+        with_samples!(*self, ref v => v.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Sets the samples in `range` to silence.
+    pub fn mute(&mut self, range: Range<usize>) {
+        with_samples!(*self, ref mut v => for s in v[range].iter_mut() {
+            *s = Sample::silence();
+        })
+    }
+
+    /// Copies the samples in `range` of `src` to this buffer, starting at
+    /// `at`. Both buffers must have the same sample type.
+    pub fn copy_from(&mut self, at: usize, src: &SampleBuffer, range: Range<usize>) {
+        let len = range.end - range.start;
+        match (self, src) {
+            (&mut SampleBuffer::S16(ref mut d), &SampleBuffer::S16(ref s)) => d[at..at + len].copy_from_slice(&s[range]),
+            (&mut SampleBuffer::S24(ref mut d), &SampleBuffer::S24(ref s)) => d[at..at + len].copy_from_slice(&s[range]),
+            (&mut SampleBuffer::S32(ref mut d), &SampleBuffer::S32(ref s)) => d[at..at + len].copy_from_slice(&s[range]),
+            (&mut SampleBuffer::F32(ref mut d), &SampleBuffer::F32(ref s)) => d[at..at + len].copy_from_slice(&s[range]),
+            (d, s) => panic!("copy from {:?} to {:?} samples", s.sample_type(), d.sample_type()),
+        }
+    }
+
+    /// Whether sample `i` is negative, used to find zero crossings.
+    pub fn is_negative(&self, i: usize) -> bool {
+        with_samples!(*self, ref v => v[i].is_negative())
+    }
+
+    /// Multiplies frame `n` (of `channels` samples) by `gain(n)`.
+    pub fn scale<F: FnMut(usize) -> f32>(&mut self, channels: usize, mut gain: F) {
+        with_samples!(*self, ref mut v => for (n, frame) in v.chunks_mut(channels).enumerate() {
+            let g = gain(n);
+            for s in frame.iter_mut() {
+                *s = s.scale(g);
+            }
+        })
+    }
+
+    /// Converts the samples to `sample_type`.
+    pub fn convert(self, sample_type: SampleType) -> SampleBuffer {
+        if self.sample_type() == sample_type {
+            return self;
+        }
+        // Go through 32 bit integers, which hold every other type without
+        // loss of precision.
+        let wide: Vec<i32> = match self {
+            SampleBuffer::S16(v) => v.into_iter().map(|s| (s as i32) << 16).collect(),
+            SampleBuffer::S24(v) => v.into_iter().map(|s| s << 8).collect(),
+            SampleBuffer::S32(v) => v,
+            SampleBuffer::F32(v) => v.into_iter()
+                .map(|s| (s.max(-1.0).min(1.0) as f64 * i32::max_value() as f64) as i32)
+                .collect(),
+        };
+        match sample_type {
+            SampleType::S16 => SampleBuffer::S16(wide.into_iter().map(|s| (s >> 16) as i16).collect()),
+            SampleType::S24 => SampleBuffer::S24(wide.into_iter().map(|s| s >> 8).collect()),
+            SampleType::S32 => SampleBuffer::S32(wide),
+            SampleType::F32 => SampleBuffer::F32(wide.into_iter()
+                .map(|s| (s as f64 / -(i32::min_value() as f64)) as f32)
+                .collect()),
+        }
+    }
+
+    /// Encodes the samples as little endian bytes, as expected by the
+    /// output.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![0; self.len() * self.sample_type().bytes()];
+        match *self {
+            SampleBuffer::S16(ref v) => LittleEndian::write_i16_into(v, &mut bytes),
+            SampleBuffer::S24(ref v) | SampleBuffer::S32(ref v) => LittleEndian::write_i32_into(v, &mut bytes),
+            SampleBuffer::F32(ref v) => LittleEndian::write_f32_into(v, &mut bytes),
+        }
+        bytes
+    }
+}
@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+use samples::{SampleBuffer, SampleType};
+
+/// Default end-to-end buffer of the server, used until it sends its settings.
+pub const DEFAULT_BUFFER_MS: i64 = 1000;
+/// Deviation (us) between the scheduled and the actual position of the
+/// audio that is tolerated before frames are dropped or silence is inserted.
+const SYNC_TOLERANCE: i64 = 10000;
+/// Deviation (us) below which no soft correction is applied.
+const SOFT_TOLERANCE: i64 = 100;
+/// Time (s) over which a deviation is corrected by soft correction.
+const CORRECTION_TIME: f64 = 5.0;
+/// Maximum playback speed change of the soft correction (0.1%).
+const MAX_CORRECTION: f64 = 0.001;
+
+/// Decoded audio together with the server time of its first frame.
+#[derive(Debug)]
+struct Chunk {
+    /// Server time (us) the first frame was captured at.
+    start: i64,
+    /// Interleaved samples.
+    samples: SampleBuffer,
+    /// Number of frames already played or dropped.
+    pos: usize
+}
+
+impl Chunk {
+    fn frames(&self, channels: usize) -> usize {
+        self.samples.len() / channels
+    }
+}
+
+/// Jitter buffer that hands out the audio due at a given server time.
+///
+/// A chunk captured at server time `t` is to be heard at
+/// `t + buffer_ms - latency_ms`, where `latency_ms` is the per-client
+/// latency configured on the server.
+/// `fill` is called with the current server time and the number of frames
+/// queued in the output. The first frame of the requested period is heard
+/// once these have been played plus `extra_latency_ms`, e.g. of an external
+/// amplifier, and exactly the frames that belong there are copied. Small
+/// deviations are corrected inaudibly by playing slightly faster or slower:
+/// single frames are dropped or repeated, preferably at zero crossings.
+/// Only when the next frame is off by more than `SYNC_TOLERANCE` are whole
+/// blocks dropped or silence inserted. At the start, when the queue has run
+/// dry and after `resync`, e.g. following an underrun of the output, this is
+/// done for any deviation above `SOFT_TOLERANCE`, so playback is in sync at
+/// once.
+///
+/// Queued audio is converted to the sample type of the output.
+#[derive(Debug)]
+pub struct Scheduler {
+    chunks: VecDeque<Chunk>,
+    rate: u32,
+    channels: usize,
+    sample_type: SampleType,
+    buffer_ms: i64,
+    latency_ms: i64,
+    extra_latency_ms: i64,
+    /// Frames to insert (positive) or drop (negative) per played frame.
+    correction: f64,
+    /// Accumulated correction not yet applied, in frames.
+    phase: f64,
+    /// Whether the last played sample of the first channel was negative.
+    last_negative: bool,
+    /// Server time (us) the most recent chunk ends at.
+    end: Option<i64>,
+    /// Whether the next deviation is corrected at once.
+    resync: bool
+}
+
+impl Scheduler {
+    pub fn new(rate: u32, channels: usize, sample_type: SampleType) -> Scheduler {
+        Scheduler {
+            chunks: VecDeque::new(),
+            rate: rate,
+            channels: channels,
+            sample_type: sample_type,
+            buffer_ms: DEFAULT_BUFFER_MS,
+            latency_ms: 0,
+            extra_latency_ms: 0,
+            correction: 0.0,
+            phase: 0.0,
+            last_negative: false,
+            end: None,
+            // Place the first audio exactly, see `resync`.
+            resync: true
+        }
+    }
+
+    /// Sets the end-to-end buffer length announced by the server.
+    pub fn set_buffer_ms(&mut self, buffer_ms: i64) {
+        self.buffer_ms = buffer_ms;
+    }
+
+    /// Sets the latency of this client; audio is played this much earlier.
+    pub fn set_latency_ms(&mut self, latency_ms: i64) {
+        self.latency_ms = latency_ms;
+    }
+
+    /// Sets the time it takes from the DAC to the speaker.
+    pub fn set_extra_latency_ms(&mut self, extra_latency_ms: i64) {
+        self.extra_latency_ms = extra_latency_ms;
+    }
+
+    /// Makes the next `fill` jump to the exact position instead of
+    /// correcting smaller deviations slowly.
+    pub fn resync(&mut self) {
+        self.resync = true;
+        self.correction = 0.0;
+        self.phase = 0.0;
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_type(&self) -> SampleType {
+        self.sample_type
+    }
+
+    /// Queues decoded `samples` captured at server time `timestamp` (us).
+    pub fn add_chunk(&mut self, timestamp: i64, samples: SampleBuffer) {
+        if samples.len() < self.channels {
+            return;
+        }
+        let chunk = Chunk {
+            start: timestamp,
+            samples: samples.convert(self.sample_type),
+            pos: 0
+        };
+        let end = timestamp + self.frames_to_us(chunk.frames(self.channels) as i64);
+        if self.end.map_or(true, |e| end > e) {
+            self.end = Some(end);
+        }
+        let idx = self.chunks.iter().position(|c| c.start > timestamp).unwrap_or(self.chunks.len());
+        self.chunks.insert(idx, chunk);
+    }
+
+    /// Returns the start (us) and length in frames of the gap between the
+    /// most recent chunk and a chunk starting at `timestamp`, if any.
+    pub fn missing_before(&self, timestamp: i64) -> Option<(i64, usize)> {
+        match self.end {
+            Some(end) if timestamp - end > SYNC_TOLERANCE => {
+                Some((end, self.us_to_frames(timestamp - end) as usize))
+            },
+            _ => None
+        }
+    }
+
+    // Computed in i128, as timestamps of a server whose clock is far off
+    // (or not yet known) can be arbitrarily far from the play time.
+    fn frames_to_us(&self, frames: i64) -> i64 {
+        (frames as i128 * 1_000_000 / self.rate as i128) as i64
+    }
+
+    fn us_to_frames(&self, us: i64) -> i64 {
+        (us as i128 * self.rate as i128 / 1_000_000) as i64
+    }
+
+    /// Fills `out` with the interleaved audio that is to be heard after the
+    /// `delay` frames queued in the output at server time `server_time` (us).
+    /// Missing audio is replaced by silence.
+    ///
+    /// Returns the first frame of `out` that holds audio and the server time
+    /// (us) it was captured at, or `None` if `out` is all silence.
+    pub fn fill(&mut self, server_time: i64, delay: usize, out: &mut SampleBuffer) -> Option<(usize, i64)> {
+        let play_time = server_time + self.frames_to_us(delay as i64) + self.extra_latency_ms * 1000;
+        let channels = self.channels;
+        let frames = out.len() / channels;
+        let tolerance = if self.resync { SOFT_TOLERANCE } else { SYNC_TOLERANCE };
+        // Server time the audio due at play_time was captured at.
+        let source_time = play_time - (self.buffer_ms - self.latency_ms) * 1000;
+        let mut first = None;
+        let mut i = 0;
+        while i < frames {
+            let wanted = source_time + self.frames_to_us(i as i64);
+            let (start, pos, len) = match self.chunks.front() {
+                Some(c) => (c.start, c.pos, c.frames(channels)),
+                None => {
+                    trace!("Buffer underrun, {} frames of silence", frames - i);
+                    // Audio arriving again is placed exactly.
+                    self.resync();
+                    break;
+                }
+            };
+            if pos >= len {
+                self.chunks.pop_front();
+                continue;
+            }
+            let next = start + self.frames_to_us(pos as i64);
+            let diff = next - wanted;
+            if diff > tolerance {
+                // Next audio is not due yet, play silence until it is.
+                let n = (self.us_to_frames(diff).max(1) as usize).min(frames - i);
+                debug!("Audio {}us early, inserting {} frames of silence", diff, n);
+                out.mute(i * channels..(i + n) * channels);
+                i += n;
+            } else if diff < -tolerance {
+                // Audio is late, skip the part that should have been played.
+                let n = (self.us_to_frames(-diff).max(1) as usize).min(len - pos);
+                debug!("Audio {}us late, dropping {} frames", -diff, n);
+                self.chunks[0].pos += n;
+            } else {
+                self.resync = false;
+                self.set_correction(diff);
+                if first.is_none() {
+                    first = Some((i, next));
+                }
+                i = self.copy(out, frames, i);
+            }
+        }
+        out.mute(i * channels..frames * channels);
+        first
+    }
+
+    /// Derives the playback speed correction from the deviation `diff` (us)
+    /// of the next frame from its scheduled time.
+    fn set_correction(&mut self, diff: i64) {
+        if diff.abs() < SOFT_TOLERANCE {
+            self.correction = 0.0;
+            self.phase = 0.0;
+            return;
+        }
+        let correction = diff as f64 / 1e6 / CORRECTION_TIME;
+        self.correction = correction.max(-MAX_CORRECTION).min(MAX_CORRECTION);
+        trace!("Audio {}us off, correcting by {:.4}%", diff, self.correction * 100.0);
+    }
+
+    /// Copies frames of the first chunk to the first `frames` frames of `out`
+    /// starting at frame `i` while applying the soft correction. Returns the
+    /// index of the next frame to be written.
+    fn copy(&mut self, out: &mut SampleBuffer, frames: usize, mut i: usize) -> usize {
+        let channels = self.channels;
+        let chunk = &mut self.chunks[0];
+        let len = chunk.samples.len() / channels;
+        while i < frames && chunk.pos < len {
+            let pos = chunk.pos;
+            let frame = pos * channels..(pos + 1) * channels;
+            let negative = chunk.samples.is_negative(frame.start);
+            self.phase += self.correction;
+            if self.phase.abs() >= 1.0 {
+                // Wait for a zero crossing, where a changed frame is least
+                // audible, unless the correction is falling behind.
+                let crossing = self.last_negative != negative;
+                if crossing || self.phase.abs() >= 2.0 {
+                    if self.phase > 0.0 {
+                        // Repeat the previous frame.
+                        self.phase -= 1.0;
+                        if pos > 0 {
+                            out.copy_from(i * channels, &chunk.samples, (pos - 1) * channels..pos * channels);
+                            i += 1;
+                            continue;
+                        }
+                    } else {
+                        // Skip this frame.
+                        self.phase += 1.0;
+                        chunk.pos += 1;
+                        continue;
+                    }
+                }
+            }
+            out.copy_from(i * channels, &chunk.samples, frame);
+            self.last_negative = negative;
+            chunk.pos += 1;
+            i += 1;
+        }
+        i
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One frame per millisecond keeps the arithmetic readable.
+    const RATE: u32 = 1000;
+
+    /// Mono scheduler due `buffer_ms - latency_ms` = 800ms after capture.
+    fn scheduler() -> Scheduler {
+        let mut s = Scheduler::new(RATE, 1, SampleType::S16);
+        s.set_buffer_ms(1000);
+        s.set_latency_ms(200);
+        s
+    }
+
+    /// Frames numbered from `first` on, with alternating signs so that every
+    /// frame is a zero crossing.
+    fn frames(first: usize, n: usize) -> SampleBuffer {
+        SampleBuffer::S16((first..first + n).map(|i| if i % 2 == 0 { i as i16 } else { -(i as i16) }).collect())
+    }
+
+    /// Fills `n` frames and returns the numbers of the frames played.
+    fn fill(s: &mut Scheduler, server_time: i64, delay: usize, n: usize) -> (Option<(usize, i64)>, Vec<i16>) {
+        let mut out = SampleBuffer::silence(SampleType::S16, n);
+        let first = s.fill(server_time, delay, &mut out);
+        match out {
+            SampleBuffer::S16(v) => (first, v.iter().map(|x| x.abs()).collect()),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn plays_chunks_back_to_back_on_schedule() {
+        let mut s = scheduler();
+        s.add_chunk(0, frames(1, 20));
+        s.add_chunk(20_000, frames(21, 20));
+        // 10 frames still queued in the output delay the period by 10ms.
+        let (first, played) = fill(&mut s, 790_000, 10, 40);
+        assert_eq!(first, Some((0, 0)));
+        assert_eq!(played, (1..41).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn drops_late_audio() {
+        let mut s = scheduler();
+        s.add_chunk(0, frames(1, 40));
+        let (first, played) = fill(&mut s, 820_000, 0, 10);
+        assert_eq!(first, Some((0, 20_000)));
+        assert_eq!(played, (21..31).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn inserts_silence_before_early_audio() {
+        let mut s = scheduler();
+        s.add_chunk(15_000, frames(1, 20));
+        let (first, played) = fill(&mut s, 800_000, 0, 30);
+        assert_eq!(first, Some((15, 15_000)));
+        let mut expected = vec![0; 15];
+        expected.extend(1..16);
+        assert_eq!(played, expected);
+    }
+
+    #[test]
+    fn reports_missing_audio() {
+        let mut s = scheduler();
+        assert_eq!(s.missing_before(0), None);
+        s.add_chunk(0, frames(1, 20));
+        assert_eq!(s.missing_before(20_000), None);
+        // Jitter within the sync tolerance is no gap.
+        assert_eq!(s.missing_before(25_000), None);
+        assert_eq!(s.missing_before(50_000), Some((20_000, 30)));
+    }
+
+    /// Counts the frames repeated and skipped in `played`.
+    fn corrections(played: &[i16]) -> (usize, usize) {
+        let repeated = played.windows(2).filter(|w| w[1] == w[0]).count();
+        let skipped = played.windows(2).filter(|w| w[1] == w[0] + 2).count();
+        (repeated, skipped)
+    }
+
+    #[test]
+    fn repeats_frames_when_audio_is_early() {
+        let mut s = scheduler();
+        s.add_chunk(0, frames(1, 5000));
+        fill(&mut s, 800_000, 0, 10);
+        // 5ms early, corrected at the maximum rate of one frame in 1000.
+        let (_, played) = fill(&mut s, 805_000, 0, 2500);
+        assert_eq!(corrections(&played), (2, 0));
+    }
+
+    #[test]
+    fn skips_frames_when_audio_is_late() {
+        let mut s = scheduler();
+        s.add_chunk(0, frames(1, 5000));
+        fill(&mut s, 800_000, 0, 10);
+        let (_, played) = fill(&mut s, 815_000, 0, 2500);
+        assert_eq!(corrections(&played), (0, 2));
+    }
+}
@@ -1,41 +1,261 @@
 use message::TimeVal;
-use std::vec::IntoIter;
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Instant;
 
-#[derive(Debug, Clone)]
+/// Number of offset samples the estimates are computed over.
+const MAX_SAMPLES: usize = 200;
+/// Minimum number of samples before a drift is estimated.
+const MIN_DRIFT_SAMPLES: usize = 20;
+/// Minimum time span (us) the samples must cover before a drift is estimated.
+const MIN_DRIFT_SPAN: i64 = 10_000_000;
+/// Drift estimates beyond this (ppm) are treated as measurement errors.
+const MAX_DRIFT_PPM: f64 = 500.0;
+
+/// Source of local time for clock synchronization.
+///
+/// Time sync timestamps and server time computation must use the same clock.
+/// Implementations other than `MonotonicClock` are mainly useful to drive
+/// the synchronization logic deterministically.
+pub trait Clock: Send + Sync {
+    /// Returns the current time in microseconds since an arbitrary, fixed
+    /// point. Must never go backwards.
+    fn now(&self) -> i64;
+}
+
+/// `Clock` based on `Instant`, i.e. CLOCK_MONOTONIC on Linux, so steps of the
+/// wall clock (NTP, manual changes) do not shift playback.
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    start: Instant
+}
+
+impl MonotonicClock {
+    pub fn new() -> Self {
+        MonotonicClock {
+            start: Instant::now()
+        }
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> i64 {
+        let elapsed = self.start.elapsed();
+        (elapsed.as_secs() as i64) * 1_000_000 + (elapsed.subsec_nanos() / 1000) as i64
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    /// Local time the sample was taken at, in microseconds.
+    local: i64,
+    /// Offset of the server clock relative to the local clock.
+    offset: i64
+}
+
+/// Estimates the offset and drift between the local clock and the server
+/// clock.
+///
+/// The local clock is a `Clock`, which must also be used to stamp the
+/// `sent` and `recieved` fields of time sync messages. The server only uses
+/// differences of these, so they need not be wall-clock times.
+///
+/// Each time sync exchange yields the four NTP timestamps: client send (t1),
+/// server receive (t2), server send (t3) and client receive (t4). The server
+/// echoes `t2 - t1` in the `Time` message body, `t4 - t3` is known locally,
+/// and the clock offset is `((t2 - t1) - (t4 - t3)) / 2`. The median over a
+/// sliding window filters out samples skewed by asymmetric network delay.
+///
+/// Over long sessions the two clocks run at slightly different rates, so the
+/// offset changes over time. The drift is estimated by a least squares fit
+/// of offset against local time and used to extrapolate the median offset to
+/// the current time. All values are signed microseconds.
+#[derive(Clone)]
 pub struct TimeProvider {
-    times: Vec<usize>
+    clock: Arc<Clock>,
+    samples: VecDeque<Sample>
 }
 
 impl TimeProvider {
-    pub fn new() -> Self {
+    pub fn new(clock: Arc<Clock>) -> Self {
         TimeProvider {
-            times: Vec::new()
+            clock: clock,
+            samples: VecDeque::new()
+        }
+    }
+
+    /// Returns the current local time in microseconds.
+    pub fn now(&self) -> i64 {
+        self.clock.now()
+    }
+
+    /// Adds a sample from one time sync round trip.
+    ///
+    /// `c2s` is the client to server difference `t2 - t1` reported by the
+    /// server, `s2c` the server to client difference `t4 - t3` and
+    /// `recieved` the local time the response arrived (t4).
+    pub fn add_time(&mut self, c2s: TimeVal, s2c: TimeVal, recieved: TimeVal) {
+        let c2s = c2s.to_micros();
+        let s2c = s2c.to_micros();
+        let offset = (c2s - s2c) / 2;
+        debug!("Time sync: offset {}us, round trip {}us", offset, c2s + s2c);
+        self.samples.push_back(Sample {
+            local: recieved.to_micros(),
+            offset: offset
+        });
+        while self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    /// Whether the server time is known, i.e. at least one time sample has
+    /// been added.
+    pub fn is_synced(&self) -> bool {
+        !self.samples.is_empty()
+    }
+
+    /// Returns the relative rate of the server clock against the local clock
+    /// in parts per million. Positive values mean the server clock runs
+    /// faster.
+    pub fn get_drift(&self) -> f64 {
+        let n = self.samples.len();
+        if n < MIN_DRIFT_SAMPLES {
+            return 0.0;
         }
+        let first = self.samples[0].local;
+        if self.samples[n - 1].local - first < MIN_DRIFT_SPAN {
+            return 0.0;
+        }
+        let mean_x = self.samples.iter().map(|s| (s.local - first) as f64).sum::<f64>() / n as f64;
+        let mean_y = self.samples.iter().map(|s| s.offset as f64).sum::<f64>() / n as f64;
+        let mut cov = 0.0;
+        let mut var = 0.0;
+        for s in self.samples.iter() {
+            let dx = (s.local - first) as f64 - mean_x;
+            cov += dx * (s.offset as f64 - mean_y);
+            var += dx * dx;
+        }
+        if var == 0.0 {
+            return 0.0;
+        }
+        let drift = cov / var * 1e6;
+        if drift.abs() > MAX_DRIFT_PPM {
+            warn!("Ignoring implausible clock drift of {:.1}ppm", drift);
+            return 0.0;
+        }
+        drift
+    }
+
+    /// Returns the offset of the server clock relative to the local clock at
+    /// local time `local`, in microseconds.
+    pub fn get_diff_to_server_at(&self, local: i64) -> i64 {
+        if self.samples.is_empty() {
+            return 0;
+        }
+        let mut offsets: Vec<i64> = self.samples.iter().map(|s| s.offset).collect();
+        offsets.sort();
+        let median = offsets[offsets.len() / 2];
+        // The median best describes the offset around the middle of the
+        // window, extrapolate from there.
+        let mut locals: Vec<i64> = self.samples.iter().map(|s| s.local).collect();
+        locals.sort();
+        let center = locals[locals.len() / 2];
+        median + ((local - center) as f64 * self.get_drift() / 1e6) as i64
+    }
+
+    /// Returns the current offset of the server clock relative to the local
+    /// clock, in microseconds.
+    pub fn get_diff_to_server(&self) -> i64 {
+        self.get_diff_to_server_at(self.now())
+    }
+
+    /// Returns the current server time in microseconds.
+    pub fn get_server_time(&self) -> i64 {
+        let now = self.now();
+        now + self.get_diff_to_server_at(now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// `Clock` that only moves when told to.
+    struct FakeClock {
+        now: Mutex<i64>
     }
-    pub fn add_time(&mut self, time: TimeVal) {
-        let diff = (time.sec as usize)*1000 + (time.usec as usize / 1000);
-        self.times.push(diff);
-        if self.times.len() > 200 {
-            let l = self.times.len() - 200;
-            self.times = self.times.split_off(l);
+
+    impl FakeClock {
+        fn new(now: i64) -> Arc<FakeClock> {
+            Arc::new(FakeClock { now: Mutex::new(now) })
+        }
+
+        fn advance(&self, us: i64) {
+            *self.now.lock().unwrap() += us;
         }
     }
-    pub fn get_diff_to_server(&self) -> usize {
-        let l = self.times.len();
-        let i = self.times.iter();
-        let s: usize = i.sum();
-        if l == 0 {
-            0
-        } else {
-            s / l
+
+    impl Clock for FakeClock {
+        fn now(&self) -> i64 {
+            *self.now.lock().unwrap()
         }
+    }
 
+    /// Simulates a time sync round trip ending now, with the server clock
+    /// `offset` ahead of the local clock and the given one-way delays.
+    fn exchange(provider: &mut TimeProvider, clock: &FakeClock, offset: i64, up: i64, down: i64) {
+        clock.advance(up + down);
+        let c2s = TimeVal::from_micros(offset + up);
+        let s2c = TimeVal::from_micros(down - offset);
+        let recieved = TimeVal::from_micros(clock.now());
+        provider.add_time(c2s, s2c, recieved);
+    }
+
+    #[test]
+    fn offset_ignores_asymmetric_samples() {
+        let clock = FakeClock::new(5_000_000);
+        let mut provider = TimeProvider::new(clock.clone());
+        assert!(!provider.is_synced());
+        let offset = 1_234_567;
+        for i in 0..15 {
+            if i % 3 == 0 {
+                // Congested uplink: alone this would put the offset 24.5ms off.
+                exchange(&mut provider, &clock, offset, 50_000, 1_000);
+            } else {
+                exchange(&mut provider, &clock, offset, 2_000, 2_000);
+            }
+            clock.advance(500_000);
+        }
+        assert!(provider.is_synced());
+        assert_eq!(provider.get_diff_to_server(), offset);
+    }
+
+    #[test]
+    fn drift_is_extrapolated() {
+        let clock = FakeClock::new(0);
+        let mut provider = TimeProvider::new(clock.clone());
+        // The server clock gains 100us per second.
+        let offset_at = |local: i64| 1_000_000 + local / 10_000;
+        for _ in 0..40 {
+            clock.advance(1_000_000);
+            let offset = offset_at(clock.now());
+            exchange(&mut provider, &clock, offset, 1_000, 1_000);
+        }
+        assert!((provider.get_drift() - 100.0).abs() < 1.0, "drift {}", provider.get_drift());
+        let later = clock.now() + 60_000_000;
+        assert!((provider.get_diff_to_server_at(later) - offset_at(later)).abs() < 100);
     }
 
-    pub fn get_server_time(&self) -> usize {
-        let time = TimeVal::new();
-        let current_time = ((time.sec as usize)*1000 + (time.usec as usize / 1000)) as usize;
-        let diff = self.get_diff_to_server() as usize;
-        current_time + diff
+    #[test]
+    fn server_time_ignores_wall_clock() {
+        // Local times are far from the wall clock, as after a step of it.
+        let clock = FakeClock::new(3_000_000);
+        let mut provider = TimeProvider::new(clock.clone());
+        let offset = 1_500_000_000_000_000;
+        exchange(&mut provider, &clock, offset, 1_000, 1_000);
+        assert_eq!(provider.get_server_time(), clock.now() + offset);
+        clock.advance(2_500_000);
+        assert_eq!(provider.get_server_time(), clock.now() + offset);
     }
 }
@@ -0,0 +1,61 @@
+use samples::SampleBuffer;
+
+/// Applies the volume and mute state sent by the server.
+pub trait VolumeControl {
+    /// Sets the volume in percent (0-100) and the mute state.
+    fn set(&mut self, volume: u16, muted: bool);
+    /// Scales the interleaved `samples` before they are written to the
+    /// output. Controls that act on the output device leave them alone.
+    fn apply(&mut self, _samples: &mut SampleBuffer, _channels: usize) {}
+}
+
+/// Software volume control applied to decoded samples.
+///
+/// The server sends volumes as percentages. Loudness is perceived roughly
+/// logarithmically, so the percentage is mapped to a gain with a cubic curve,
+/// which approximates a 60dB range. Gain changes are ramped over one period
+/// to avoid zipper noise.
+#[derive(Debug, Clone)]
+pub struct SoftwareVolume {
+    /// Gain currently applied to the samples.
+    current: f32,
+    volume: u16,
+    muted: bool
+}
+
+impl SoftwareVolume {
+    pub fn new() -> SoftwareVolume {
+        SoftwareVolume {
+            current: 1.0,
+            volume: 100,
+            muted: false
+        }
+    }
+
+    fn target(&self) -> f32 {
+        if self.muted {
+            return 0.0;
+        }
+        let v = self.volume as f32 / 100.0;
+        v * v * v
+    }
+}
+
+impl VolumeControl for SoftwareVolume {
+    fn set(&mut self, volume: u16, muted: bool) {
+        self.volume = volume.min(100);
+        self.muted = muted;
+    }
+
+    fn apply(&mut self, samples: &mut SampleBuffer, channels: usize) {
+        let target = self.target();
+        if self.current == 1.0 && target == 1.0 {
+            return;
+        }
+        let frames = samples.len() / channels;
+        let start = self.current;
+        let step = (target - start) / frames.max(1) as f32;
+        samples.scale(channels, |n| start + step * (n + 1) as f32);
+        self.current = target;
+    }
+}